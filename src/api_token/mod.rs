@@ -0,0 +1,307 @@
+use crate::error::{stored_id, AppError, AppResult, FieldError};
+use crate::session::Credentials;
+use crate::utils::token;
+use crate::view::{Json, View};
+use crate::{auth::Auth, session::Session, AppState};
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, TimeZone, Utc};
+use mongodb::bson::{self, oid::ObjectId};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+/// Days a token lasts when created without `expires_in_days`.
+const DEFAULT_LIFETIME: i64 = 30;
+const MAX_LIFETIME: i64 = 365;
+/// Marks the plain tokens, so they are easy to spot in logs and secret scanners.
+const TOKEN_PREFIX: &str = "blog_pat_";
+
+/// What a personal access token may be used for. Writing to a resource includes
+/// reading it.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Scope {
+    #[serde(rename = "posts:read")]
+    PostsRead,
+    #[serde(rename = "posts:write")]
+    PostsWrite,
+    #[serde(rename = "comments:read")]
+    CommentsRead,
+    #[serde(rename = "comments:write")]
+    CommentsWrite,
+    #[serde(rename = "tags:write")]
+    TagsWrite,
+    #[serde(rename = "users:read")]
+    UsersRead,
+    #[serde(rename = "users:write")]
+    UsersWrite,
+    /// Managing the account itself: credentials, sessions and tokens. Never granted
+    /// to a token, so only a signed in session passes.
+    #[serde(skip)]
+    Account,
+}
+
+impl Scope {
+    fn covers(self, needed: Scope) -> bool {
+        self == needed
+            || matches!(
+                (self, needed),
+                (Scope::PostsWrite, Scope::PostsRead)
+                    | (Scope::CommentsWrite, Scope::CommentsRead)
+                    | (Scope::UsersWrite, Scope::UsersRead)
+            )
+    }
+}
+
+/// A named token for scripts and CI, sent as `Authorization: Bearer`. Only its hash
+/// is stored.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct ApiToken {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub auth_id: ObjectId,
+    pub name: String,
+    pub token_hash: String,
+    pub scopes: Vec<Scope>,
+    /// BSON date, so the TTL index can purge expired tokens.
+    pub expires_at: bson::DateTime,
+    #[serde(default)]
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+/// A token as shown to its owner. `token` is only there in the response creating it.
+#[derive(Serialize, Debug)]
+pub(crate) struct ApiTokenView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_at: DateTime<Utc>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl View for ApiTokenView {}
+
+impl From<ApiToken> for ApiTokenView {
+    fn from(api_token: ApiToken) -> Self {
+        ApiTokenView {
+            id: api_token.id,
+            name: api_token.name,
+            scopes: api_token.scopes,
+            expires_at: Utc
+                .timestamp_millis_opt(api_token.expires_at.timestamp_millis())
+                .single()
+                .unwrap_or_default(),
+            last_used_at: api_token.last_used_at,
+            token: None,
+            created_at: api_token.created_at,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateApiToken {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    pub expires_in_days: Option<i64>,
+}
+
+impl ApiToken {
+    pub(crate) async fn create_indexes(
+        api_tokens_collection: &Collection<ApiToken>,
+    ) -> mongodb::error::Result<()> {
+        let token_index = IndexModel::builder()
+            .keys(bson::doc! { "token_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let auth_index = IndexModel::builder()
+            .keys(bson::doc! { "auth_id": 1 })
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        api_tokens_collection
+            .create_indexes([token_index, auth_index, ttl_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Loads the account `token` belongs to. Fails with `AppError::Unauthorized` for
+    /// an unknown or expired token and with `AppError::Forbidden` when none of its
+    /// scopes covers `scope`.
+    pub(crate) async fn authenticate(state: &AppState, token: &str, scope: Scope) -> AppResult<Auth> {
+        let now = Utc::now();
+        let api_token = state
+            .api_tokens_collection
+            .find_one_and_update(
+                bson::doc! { "token_hash": token::hash(token) },
+                bson::doc! { "$set": { "last_used_at": bson::to_bson(&now)? } },
+                None,
+            )
+            .await?;
+        let Some(api_token) = api_token else { return Err(AppError::Unauthorized) };
+        // the TTL index only purges about once a minute
+        if api_token.expires_at.timestamp_millis() <= now.timestamp_millis() {
+            return Err(AppError::Unauthorized);
+        }
+        if !api_token.scopes.iter().any(|granted| granted.covers(scope)) {
+            return Err(AppError::Forbidden);
+        }
+
+        let auth = state
+            .auths_collection
+            .find_one(bson::doc! { "_id": api_token.auth_id }, None)
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::Unauthorized) };
+
+        Ok(auth)
+    }
+
+    /// Creates a token for the signed in account. The plain token is in this response
+    /// only.
+    pub(crate) async fn create(
+        credentials: Credentials,
+        State(state): State<AppState>,
+        Json(json): Json<CreateApiToken>,
+    ) -> AppResult<(StatusCode, Json<ApiTokenView>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        let auth_id = stored_id(auth.id)?;
+
+        let mut errors = vec![];
+        let name = json.name.trim().to_string();
+        if name.is_empty() {
+            errors.push(FieldError::new("name", "must not be empty"));
+        }
+        if json.scopes.is_empty() {
+            errors.push(FieldError::new("scopes", "must not be empty"));
+        }
+        let lifetime = json.expires_in_days.unwrap_or(DEFAULT_LIFETIME);
+        if !(1..=MAX_LIFETIME).contains(&lifetime) {
+            errors.push(FieldError::new("expires_in_days", format!("must be between 1 and {MAX_LIFETIME}")));
+        }
+        if !errors.is_empty() {
+            return Err(AppError::Validation(errors));
+        }
+
+        let mut scopes: Vec<Scope> = vec![];
+        for scope in json.scopes {
+            if !scopes.contains(&scope) {
+                scopes.push(scope);
+            }
+        }
+        let plain = format!("{TOKEN_PREFIX}{}", nanoid!(40));
+        let now = Utc::now();
+        let expires_at = now + Duration::days(lifetime);
+        let mut api_token = ApiToken {
+            id: None,
+            auth_id,
+            name,
+            token_hash: token::hash(&plain),
+            scopes,
+            expires_at: bson::DateTime::from_millis(expires_at.timestamp_millis()),
+            last_used_at: None,
+            created_at: Some(now),
+        };
+
+        let document = state.api_tokens_collection.insert_one(&api_token, None).await?;
+        api_token.id = document.inserted_id.as_object_id();
+
+        let mut view = ApiTokenView::from(api_token);
+        view.token = Some(plain);
+
+        Ok((StatusCode::CREATED, Json(view)))
+    }
+
+    /// Lists the tokens of the signed in account, newest first.
+    pub(crate) async fn read_all(
+        credentials: Credentials,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<Vec<ApiTokenView>>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        let auth_id = stored_id(auth.id)?;
+
+        let options = FindOptions::builder().sort(bson::doc! { "_id": -1 }).build();
+        let mut cursor = state
+            .api_tokens_collection
+            .find(bson::doc! { "auth_id": auth_id }, options)
+            .await?;
+
+        let mut api_tokens: Vec<ApiTokenView> = vec![];
+        while let Some(api_token) = cursor.next().await {
+            api_tokens.push(ApiTokenView::from(api_token?));
+        }
+
+        Ok((StatusCode::FOUND, Json(api_tokens)))
+    }
+
+    /// Revokes one of the signed in account's tokens.
+    pub(crate) async fn delete(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+    ) -> AppResult<StatusCode> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        let auth_id = stored_id(auth.id)?;
+
+        let result = state
+            .api_tokens_collection
+            .delete_one(bson::doc! { "_id": id, "auth_id": auth_id }, None)
+            .await?;
+        if result.deleted_count == 0 {
+            return Err(AppError::NotFound);
+        }
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn scopes_cover_themselves() {
+        for scope in [Scope::PostsRead, Scope::TagsWrite, Scope::UsersWrite, Scope::Account] {
+            assert!(scope.covers(scope));
+        }
+    }
+
+    #[test]
+    fn write_scopes_cover_reading_the_same_thing() {
+        assert!(Scope::PostsWrite.covers(Scope::PostsRead));
+        assert!(Scope::CommentsWrite.covers(Scope::CommentsRead));
+        assert!(Scope::UsersWrite.covers(Scope::UsersRead));
+    }
+
+    #[test]
+    fn scopes_do_not_cover_more_than_that() {
+        assert!(!Scope::PostsRead.covers(Scope::PostsWrite));
+        assert!(!Scope::PostsWrite.covers(Scope::CommentsWrite));
+        assert!(!Scope::TagsWrite.covers(Scope::PostsRead));
+        assert!(!Scope::UsersWrite.covers(Scope::Account));
+    }
+
+    #[test]
+    fn account_scope_cannot_be_requested() {
+        assert!(serde_json::from_str::<Scope>("\"posts:write\"").is_ok());
+        assert!(serde_json::from_str::<Scope>("\"Account\"").is_err());
+        assert!(serde_json::from_str::<Scope>("\"account\"").is_err());
+    }
+}
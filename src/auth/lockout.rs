@@ -0,0 +1,331 @@
+use crate::api_token::Scope;
+use crate::error::{AppError, AppResult};
+use crate::session::{Credentials, Session};
+use crate::user::Permission;
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+/// Failed attempts allowed before each further one has to wait, twice as long every
+/// time.
+const FREE_ATTEMPTS: i64 = 3;
+const DEFAULT_EMAIL_THRESHOLD: i64 = 10;
+const DEFAULT_IP_THRESHOLD: i64 = 50;
+const DEFAULT_LOCKOUT_MINUTES: i64 = 15;
+
+/// When failed sign ins lock an email or a client IP out. Read from
+/// `BLOG_LOCKOUT_THRESHOLD`, `BLOG_LOCKOUT_IP_THRESHOLD` and `BLOG_LOCKOUT_MINUTES`.
+#[derive(Clone, Debug)]
+pub(crate) struct LockoutPolicy {
+    /// Failures per email before it is locked out.
+    pub email_threshold: i64,
+    /// Failures per client IP before it is locked out, higher since many people can
+    /// share one.
+    pub ip_threshold: i64,
+    /// How long a lockout lasts, and how long failures are remembered.
+    pub duration: Duration,
+}
+
+impl LockoutPolicy {
+    pub(crate) fn from_env() -> Self {
+        let number = |name: &str, default: i64| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .filter(|value: &i64| *value > 0)
+                .unwrap_or(default)
+        };
+
+        LockoutPolicy {
+            email_threshold: number("BLOG_LOCKOUT_THRESHOLD", DEFAULT_EMAIL_THRESHOLD),
+            ip_threshold: number("BLOG_LOCKOUT_IP_THRESHOLD", DEFAULT_IP_THRESHOLD),
+            duration: Duration::minutes(number("BLOG_LOCKOUT_MINUTES", DEFAULT_LOCKOUT_MINUTES)),
+        }
+    }
+}
+
+/// Recent failed sign ins for one email or one client IP.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct SignInThrottle {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// `email:<address>` or `ip:<address>`.
+    pub key: String,
+    pub failures: i64,
+    /// Attempts before this are rejected without checking the password.
+    #[serde(default)]
+    pub blocked_until: Option<DateTime<Utc>>,
+    /// BSON date, so the TTL index forgets the failures after a quiet period.
+    pub expires_at: bson::DateTime,
+}
+
+/// A lockout, kept for those managing users to review and lift.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct LockoutEvent {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key: String,
+    pub failures: i64,
+    pub locked_until: DateTime<Utc>,
+    /// Client IP of the attempt that triggered the lockout.
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub cleared_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct LockoutEventView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub key: String,
+    pub failures: i64,
+    pub locked_until: DateTime<Utc>,
+    pub ip: Option<String>,
+    pub cleared_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl View for LockoutEventView {}
+
+impl From<LockoutEvent> for LockoutEventView {
+    fn from(event: LockoutEvent) -> Self {
+        LockoutEventView {
+            id: event.id,
+            key: event.key,
+            failures: event.failures,
+            locked_until: event.locked_until,
+            ip: event.ip,
+            cleared_at: event.cleared_at,
+            created_at: event.created_at,
+        }
+    }
+}
+
+fn email_key(email: &str) -> String {
+    format!("email:{}", email.trim().to_lowercase())
+}
+
+fn keys(email: Option<&str>, ip: Option<&str>) -> Vec<String> {
+    let mut keys: Vec<String> = email.map(email_key).into_iter().collect();
+    if let Some(ip) = ip {
+        keys.push(format!("ip:{ip}"));
+    }
+    keys
+}
+
+fn to_bson_date(date: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(date.timestamp_millis())
+}
+
+impl SignInThrottle {
+    pub(crate) async fn create_indexes(
+        sign_in_throttles_collection: &Collection<SignInThrottle>,
+    ) -> mongodb::error::Result<()> {
+        let key_index = IndexModel::builder()
+            .keys(bson::doc! { "key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        sign_in_throttles_collection
+            .create_indexes([key_index, ttl_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Fails with `AppError::TooManyRequests` while signing in as `email`, or from
+    /// `ip`, has to wait.
+    pub(crate) async fn check(state: &AppState, email: &str, ip: Option<&str>) -> AppResult<()> {
+        SignInThrottle::check_keys(state, keys(Some(email), ip)).await
+    }
+
+    /// Like `check`, for sign ins that do not know the email yet.
+    pub(crate) async fn check_ip(state: &AppState, ip: Option<&str>) -> AppResult<()> {
+        SignInThrottle::check_keys(state, keys(None, ip)).await
+    }
+
+    async fn check_keys(state: &AppState, keys: Vec<String>) -> AppResult<()> {
+        let now = Utc::now();
+        let mut cursor = state
+            .sign_in_throttles_collection
+            .find(bson::doc! { "key": { "$in": keys } }, None)
+            .await?;
+
+        let mut wait = Duration::zero();
+        while let Some(throttle) = cursor.next().await {
+            if let Some(blocked_until) = throttle?.blocked_until {
+                wait = wait.max(blocked_until - now);
+            }
+        }
+
+        if wait > Duration::zero() {
+            // round up, retrying a moment too early would just fail again
+            let retry_after = (wait.num_milliseconds() as u64).div_ceil(1000);
+            return Err(AppError::TooManyRequests { retry_after });
+        }
+        Ok(())
+    }
+
+    /// Counts a failed sign in as `email` from `ip`, delaying the next attempt or
+    /// locking them out once over the threshold.
+    pub(crate) async fn fail(state: &AppState, email: &str, ip: Option<&str>) -> AppResult<()> {
+        SignInThrottle::fail_keys(state, keys(Some(email), ip), ip).await
+    }
+
+    /// Like `fail`, for sign ins that failed before the email was known.
+    pub(crate) async fn fail_ip(state: &AppState, ip: Option<&str>) -> AppResult<()> {
+        SignInThrottle::fail_keys(state, keys(None, ip), ip).await
+    }
+
+    async fn fail_keys(state: &AppState, keys: Vec<String>, ip: Option<&str>) -> AppResult<()> {
+        let policy = &state.lockout;
+        let now = Utc::now();
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        for key in keys {
+            let threshold = match key.starts_with("ip:") {
+                true => policy.ip_threshold,
+                false => policy.email_threshold,
+            };
+
+            let throttle = state
+                .sign_in_throttles_collection
+                .find_one_and_update(
+                    bson::doc! { "key": &key },
+                    bson::doc! {
+                        "$inc": { "failures": 1 },
+                        "$set": { "expires_at": to_bson_date(now + policy.duration) },
+                    },
+                    options.clone(),
+                )
+                .await?;
+            let Some(throttle) = throttle else { continue };
+
+            if throttle.failures >= threshold {
+                // failures start over, so the backoff does too once the lockout ends
+                let locked_until = now + policy.duration;
+                state
+                    .sign_in_throttles_collection
+                    .update_one(
+                        bson::doc! { "_id": throttle.id },
+                        bson::doc! { "$set": {
+                            "failures": 0,
+                            "blocked_until": bson::to_bson(&locked_until)?,
+                            "expires_at": to_bson_date(locked_until + policy.duration),
+                        } },
+                        None,
+                    )
+                    .await?;
+                state
+                    .lockout_events_collection
+                    .insert_one(
+                        LockoutEvent {
+                            id: None,
+                            key,
+                            failures: throttle.failures,
+                            locked_until,
+                            ip: ip.map(String::from),
+                            cleared_at: None,
+                            created_at: Some(now),
+                        },
+                        None,
+                    )
+                    .await?;
+            } else if throttle.failures > FREE_ATTEMPTS {
+                let exponent = (throttle.failures - FREE_ATTEMPTS - 1).min(20) as u32;
+                let delay = Duration::seconds(2_i64.pow(exponent)).min(policy.duration);
+                state
+                    .sign_in_throttles_collection
+                    .update_one(
+                        bson::doc! { "_id": throttle.id },
+                        bson::doc! { "$set": { "blocked_until": bson::to_bson(&(now + delay))? } },
+                        None,
+                    )
+                    .await?;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Forgets the failures of `email` after it signed in. Those of the client IP stay,
+    /// or guessing at many accounts could be reset with one known password.
+    pub(crate) async fn succeed(state: &AppState, email: &str) -> AppResult<()> {
+        state
+            .sign_in_throttles_collection
+            .delete_one(bson::doc! { "key": email_key(email) }, None)
+            .await?;
+
+        Ok(())
+    }
+}
+
+impl LockoutEvent {
+    pub(crate) async fn read_all(
+        credentials: Credentials,
+        params: ListParams<NoFilter>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<LockoutEventView>>)> {
+        Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
+
+        let page = params
+            .find_page(&state.lockout_events_collection, bson::doc! {})
+            .await?;
+
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(LockoutEventView::from))))
+    }
+
+    /// Lifts a lockout early by forgetting the failures of its email or IP.
+    pub(crate) async fn clear(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<LockoutEventView>)> {
+        Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let event = state
+            .lockout_events_collection
+            .find_one_and_update(
+                bson::doc! { "_id": id },
+                bson::doc! { "$set": { "cleared_at": bson::to_bson(&Utc::now())? } },
+                options,
+            )
+            .await?;
+        let Some(event) = event else { return Err(AppError::NotFound) };
+
+        state
+            .sign_in_throttles_collection
+            .delete_one(bson::doc! { "key": &event.key }, None)
+            .await?;
+
+        Ok((StatusCode::OK, Json(LockoutEventView::from(event))))
+    }
+}
@@ -0,0 +1,222 @@
+use super::{Auth, RequestLimit, SignInThrottle};
+use crate::cookie::SetCookies;
+use crate::error::{AppError, AppResult};
+use crate::mail::Mail;
+use crate::session::{ClientInfo, Session, SessionView};
+use crate::utils::token;
+use crate::view::Json;
+use crate::AppState;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+
+const DEFAULT_LIFETIME_MINUTES: i64 = 15;
+
+/// Sign in by a link mailed to the account's address. Enabled by setting
+/// `BLOG_MAGIC_LINK` to `true`, with links lasting `BLOG_MAGIC_LINK_MINUTES`.
+#[derive(Clone, Debug)]
+pub(crate) struct MagicLinkConfig {
+    pub lifetime: Duration,
+}
+
+impl MagicLinkConfig {
+    pub(crate) fn from_env() -> Option<Self> {
+        if std::env::var("BLOG_MAGIC_LINK").ok()? != "true" {
+            return None;
+        }
+        let minutes = std::env::var("BLOG_MAGIC_LINK_MINUTES")
+            .ok()
+            .and_then(|value| value.parse().ok())
+            .filter(|value: &i64| *value > 0)
+            .unwrap_or(DEFAULT_LIFETIME_MINUTES);
+
+        Some(MagicLinkConfig { lifetime: Duration::minutes(minutes) })
+    }
+}
+
+/// A mailed sign in link not used yet. Only the hash of its token is stored.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct MagicLink {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub auth_id: ObjectId,
+    pub token_hash: String,
+    /// BSON date, so the TTL index can purge unused links.
+    pub expires_at: bson::DateTime,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RequestMagicLink {
+    pub email: String,
+}
+
+impl MagicLink {
+    pub(crate) async fn create_indexes(
+        magic_links_collection: &Collection<MagicLink>,
+    ) -> mongodb::error::Result<()> {
+        let token_index = IndexModel::builder()
+            .keys(bson::doc! { "token_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        magic_links_collection
+            .create_indexes([token_index, ttl_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replaces any unused link of the account registered under `email` with a new
+    /// one and mails it.
+    async fn issue(state: &AppState, config: &MagicLinkConfig, email: &str) -> AppResult<()> {
+        let Some(auth) = Auth::find_by_email(state, email).await? else { return Ok(()) };
+        let Some(auth_id) = auth.id else { return Ok(()) };
+
+        state
+            .magic_links_collection
+            .delete_many(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+
+        let link_token = nanoid!(32);
+        let now = Utc::now();
+        let expires_at = now + config.lifetime;
+        state
+            .magic_links_collection
+            .insert_one(
+                MagicLink {
+                    id: None,
+                    auth_id,
+                    token_hash: token::hash(&link_token),
+                    expires_at: bson::DateTime::from_millis(expires_at.timestamp_millis()),
+                    created_at: Some(now),
+                },
+                None,
+            )
+            .await?;
+
+        let body = format!(
+            "Someone asked to sign in to your {} account. If it was you, open this link within {} minutes:\n\n{}\n\nOtherwise you can ignore this mail.\n",
+            state.site_title,
+            config.lifetime.num_minutes(),
+            state.links.magic_link(&link_token)
+        );
+        state
+            .mailer
+            .send(Mail {
+                to: auth.email,
+                subject: format!("Sign in to {}", state.site_title),
+                body,
+            })
+            .await
+    }
+}
+
+impl Auth {
+    /// Mails a sign in link. Responds the same way whether or not an account uses
+    /// `email`. Refused while sign ins as `email` are locked out, and limited per
+    /// email and client IP so inboxes cannot be flooded. Requests never count as
+    /// failed sign ins, or anyone could lock someone else out by asking for links.
+    pub(crate) async fn request_magic_link(
+        client: ClientInfo,
+        State(state): State<AppState>,
+        Json(json): Json<RequestMagicLink>,
+    ) -> AppResult<StatusCode> {
+        let Some(config) = state.magic_link.clone() else { return Err(AppError::NotFound) };
+        let ip = client.ip.as_deref();
+        SignInThrottle::check(&state, &json.email, ip).await?;
+        RequestLimit::hit(&state, "magic_link", &json.email, ip).await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = MagicLink::issue(&state, &config, json.email.trim()).await {
+                eprintln!("Failed to issue a magic link: {error:?}");
+            }
+        });
+
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    /// Uses up a mailed sign in link and starts a session, just like `Auth::sign_in`.
+    /// Opening the link also proves the address, so it verifies the email.
+    pub(crate) async fn consume_magic_link(
+        client: ClientInfo,
+        Path(link_token): Path<String>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, SetCookies, Json<SessionView>)> {
+        if state.magic_link.is_none() {
+            return Err(AppError::NotFound);
+        }
+
+        let ip = client.ip.as_deref();
+        SignInThrottle::check_ip(&state, ip).await?;
+
+        let link = state
+            .magic_links_collection
+            .find_one(bson::doc! { "token_hash": token::hash(&link_token) }, None)
+            .await?;
+        let invalid = || AppError::BadRequest(String::from("The sign in link is invalid or expired."));
+        let Some(link) = link else { return Err(invalid()) };
+        if link.expires_at.timestamp_millis() < Utc::now().timestamp_millis() {
+            return Err(invalid());
+        }
+
+        let auth = state
+            .auths_collection
+            .find_one(bson::doc! { "_id": link.auth_id }, None)
+            .await?;
+        let Some(auth) = auth else { return Err(invalid()) };
+        SignInThrottle::check(&state, &auth.email, ip).await?;
+
+        // the link is only used up once it can be, and only by one request
+        let deleted = state
+            .magic_links_collection
+            .delete_one(bson::doc! { "_id": link.id }, None)
+            .await?;
+        if deleted.deleted_count == 0 {
+            return Err(invalid());
+        }
+        SignInThrottle::succeed(&state, &auth.email).await?;
+
+        if !auth.is_verified() {
+            let now = bson::to_bson(&Utc::now())?;
+            state
+                .auths_collection
+                .update_one(
+                    bson::doc! { "_id": link.auth_id },
+                    bson::doc! {
+                        "$set": { "email_verified_at": &now, "updatedAt": &now },
+                        "$unset": { "verification_nonce": "" },
+                    },
+                    None,
+                )
+                .await?;
+        }
+
+        let session = Session::start(&state, link.auth_id, client, auth.totp_enabled).await?;
+        let status = match session.two_factor_pending {
+            true => StatusCode::ACCEPTED,
+            false => StatusCode::OK,
+        };
+
+        Ok((
+            status,
+            state.cookies.sign_in(&session.session_id),
+            Json(session.into_view(true)),
+        ))
+    }
+}
@@ -1,34 +1,106 @@
+mod lockout;
+mod magic_link;
+mod oidc;
+mod password_reset;
+mod policy;
+mod request_limit;
+mod two_factor;
+mod verification;
+
+use crate::api_token::Scope;
+use crate::cookie::SetCookies;
+use crate::error::{stored_id, AppError, AppResult};
+use crate::session::{ClientInfo, Credentials, SessionView};
 use crate::utils::database::Crud;
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::view::{Json, View};
 use crate::AppState;
-use crate::{session::Session, user::Role};
+use crate::session::Session;
+use crate::user::{Permission, User};
 use async_trait::async_trait;
 use axum::{
     extract::{Path, State},
     headers::Cookie,
-    http::{header::SET_COOKIE, StatusCode},
-    response::{AppendHeaders, IntoResponse},
-    Json, TypedHeader,
+    http::{HeaderMap, StatusCode},
+    TypedHeader,
 };
-use bcrypt::{hash, verify, DEFAULT_COST};
-use chrono::{DateTime, Duration, Utc};
-use futures::StreamExt;
+use chrono::{DateTime, Utc};
 use mongodb::bson;
 use mongodb::bson::oid::ObjectId;
-use nanoid::nanoid;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
 use serde::{Deserialize, Serialize};
+pub(crate) use lockout::{LockoutEvent, LockoutPolicy, SignInThrottle};
+pub(crate) use magic_link::{MagicLink, MagicLinkConfig};
+pub(crate) use oidc::OidcConfig;
+pub(crate) use password_reset::PasswordReset;
+pub(crate) use policy::PasswordPolicy;
+pub(crate) use request_limit::RequestLimit;
+pub(crate) use verification::Restrictions;
 
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub(crate) struct Auth {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub email: String,
+    /// `email` trimmed and lowercased, unique so case cannot make a second account.
+    pub email_normalized: String,
     pub password_hash: String,
+    /// Unset until the owner opens the link mailed by `Auth::send_verification`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub email_verified_at: Option<DateTime<Utc>>,
+    /// Nonce of the only verification token still accepted.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub verification_nonce: Option<String>,
+    /// Base32 TOTP secret, set by `Auth::enroll_two_factor`. Only asked for at sign in
+    /// once `totp_enabled` confirms the authenticator app has it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_secret: Option<String>,
+    #[serde(default)]
+    pub totp_enabled: bool,
+    /// Last TOTP time step accepted, so a code cannot be used twice.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub totp_last_step: Option<i64>,
+    /// Hashes of the unused recovery codes, each good for one sign in.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub recovery_codes: Vec<String>,
+    /// `issuer|subject` of the OIDC identity linked to the account.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub oidc_subject: Option<String>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// What an account looks like to clients, without its password hash.
+#[derive(Serialize, Debug)]
+pub(crate) struct AuthView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub email: String,
+    pub email_verified: bool,
+    pub two_factor_enabled: bool,
     #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime<Utc>>,
     #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+impl View for AuthView {}
+
+impl From<Auth> for AuthView {
+    fn from(auth: Auth) -> Self {
+        AuthView {
+            email_verified: auth.is_verified(),
+            two_factor_enabled: auth.totp_enabled,
+            id: auth.id,
+            email: auth.email,
+            created_at: auth.created_at,
+            updated_at: auth.updated_at,
+        }
+    }
+}
+
 #[derive(Deserialize)]
 pub(crate) struct SignInAuth {
     pub email: String,
@@ -36,256 +108,284 @@ pub(crate) struct SignInAuth {
 }
 
 impl Auth {
+    /// Trades the current session cookie for a fresh `session_id`.
     pub(crate) async fn sign_in_session(
-        TypedHeader(typed_header): TypedHeader<Cookie>,
+        TypedHeader(cookie): TypedHeader<Cookie>,
         State(state): State<AppState>,
-    ) -> (StatusCode, impl IntoResponse) {
-        let session_id = typed_header.get("session_id");
-        if let None = session_id {
-            return (
-                StatusCode::UNAUTHORIZED,
-                AppendHeaders([(SET_COOKIE, String::from(""))]),
-            );
-        }
-        let session_id = session_id.expect("Session not found and not caught at compile time");
-        let session_user = state
-            .sessions_collection
-            .find_one(bson::doc! { "session_id": session_id }, None)
-            .await
-            .expect("Failed to execute find_one");
-        match session_user {
-            Some(session) => {
-                if Utc::now() <= session.valid_until {
-                    return (
-                        StatusCode::UNAUTHORIZED,
-                        AppendHeaders([(SET_COOKIE, String::from(""))]),
-                    );
-                }
+    ) -> AppResult<(StatusCode, SetCookies, Json<SessionView>)> {
+        let Some(mut session) = Session::current(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+        session.rotate(&state).await?;
+
+        Ok((
+            StatusCode::OK,
+            state.cookies.sign_in(&session.session_id),
+            Json(session.into_view(true)),
+        ))
+    }
+
+    pub(crate) async fn sign_in(
+        client: ClientInfo,
+        State(state): State<AppState>,
+        Json(json): Json<SignInAuth>,
+    ) -> AppResult<(StatusCode, SetCookies, Json<SessionView>)> {
+        let ip = client.ip.as_deref();
+        SignInThrottle::check(&state, &json.email, ip).await?;
+
+        let auth = Auth::find_by_email(&state, &json.email).await?;
+        let verified = match &auth {
+            // accounts made through OIDC have no password until one is reset
+            Some(auth) if !auth.password_hash.is_empty() => {
+                state
+                    .passwords
+                    .verify(json.password.clone(), auth.password_hash.clone())
+                    .await?
             }
-            None => {
-                return (
-                    StatusCode::UNAUTHORIZED,
-                    AppendHeaders([(SET_COOKIE, String::from(""))]),
-                );
+            _ => {
+                state.passwords.verify_dummy(json.password.clone()).await?;
+                false
             }
+        };
+        if !verified {
+            SignInThrottle::fail(&state, &json.email, ip).await?;
+            return Err(AppError::Unauthorized);
         }
+        let Some(auth) = auth else { return Err(AppError::Unauthorized) };
+        let auth_id = stored_id(auth.id)?;
+        SignInThrottle::succeed(&state, &json.email).await?;
 
-        let new_session_id = nanoid!();
-        let _ = state
-            .sessions_collection
+        // the plain password is only at hand now, a failed upgrade can wait for the next sign in
+        if state.passwords.needs_rehash(&auth.password_hash) {
+            if let Err(error) = Auth::rehash_password(&state, &auth, json.password).await {
+                eprintln!("Failed to rehash a password: {error:?}");
+            }
+        }
+
+        // with two factor authentication the session only becomes usable through
+        // `Auth::verify_two_factor`
+        let session = Session::start(&state, auth_id, client, auth.totp_enabled).await?;
+        let status = match session.two_factor_pending {
+            true => StatusCode::ACCEPTED,
+            false => StatusCode::OK,
+        };
+
+        Ok((
+            status,
+            state.cookies.sign_in(&session.session_id),
+            Json(session.into_view(true)),
+        ))
+    }
+
+    /// Replaces the stored hash of `auth` with one made the way `state.passwords` hashes
+    /// now, unless the password changed in the meantime.
+    async fn rehash_password(state: &AppState, auth: &Auth, password: String) -> AppResult<()> {
+        let password_hash = state.passwords.hash(password).await?;
+        state
+            .auths_collection
             .update_one(
-                bson::doc! { "session_id": session_id },
-                bson::doc! { "$set": { "session_id": &new_session_id }},
+                bson::doc! { "_id": auth.id, "password_hash": &auth.password_hash },
+                bson::doc! { "$set": { "password_hash": password_hash } },
                 None,
             )
-            .await
-            .expect("Failed to execute update_one");
+            .await?;
 
-        (
-            StatusCode::OK,
-            AppendHeaders([(SET_COOKIE, format!("session_id={}", &new_session_id))]),
-        )
+        Ok(())
     }
 
-    pub(crate) async fn sign_in(
+    /// Revokes everything that was handed out against the old password of `auth_id`:
+    /// its sessions except `keep`, its API tokens and its unused magic links.
+    pub(crate) async fn revoke_credentials(
+        state: &AppState,
+        auth_id: ObjectId,
+        keep: Option<ObjectId>,
+    ) -> AppResult<()> {
+        Session::revoke_others(state, auth_id, keep).await?;
+        state
+            .api_tokens_collection
+            .delete_many(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+        state
+            .magic_links_collection
+            .delete_many(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Ends the session the request was made with and clears its cookie.
+    pub(crate) async fn sign_out(
+        TypedHeader(cookie): TypedHeader<Cookie>,
         State(state): State<AppState>,
-        Json(json): Json<SignInAuth>,
-    ) -> (StatusCode, impl IntoResponse) {
-        let auth = state
-            .auths_collection
-            .find_one(bson::doc! { "email": &json.email }, None)
-            .await
-            .expect("Failed to execute find_one")
-            .expect("Failed to get an auth");
-
-        let user_query = state
-            .users_collection
-            .find_one(bson::doc! { "auth_id": auth.id }, None)
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, AppendHeaders([(SET_COOKIE, String::from(""))])) };
-
-        let password_is_correct = verify(&json.password, &auth.password_hash).unwrap();
-        let session_id = nanoid!();
-        let now = Utc::now();
-        if password_is_correct {
-            let user_session: Option<Session> = state
-                .sessions_collection
-                .find_one(bson::doc! { "auth_id": auth.id }, None)
-                .await
-                .expect("Failed to execute find_one");
-            match user_session {
-                Some(session) => {
-                    state
-                        .sessions_collection
-                        .delete_one(bson::doc! { "_id": session.id }, None)
-                        .await
-                        .expect("Failed to delete existent user session");
-                }
-                None => (),
-            };
-            state
-                .sessions_collection
-                .insert_one(
-                    Session {
-                        id: None,
-                        auth_id: auth.id.expect("User has no id"),
-                        user_id: match user {
-                            Some(user) => user.id,
-                            None => None,
-                        },
-                        session_id: session_id.to_string(),
-                        valid_until: now + Duration::days(7),
-                        created_at: Some(now),
-                        updated_at: Some(now),
-                    },
-                    None,
-                )
-                .await
-                .expect("Failed to execute insert_one");
-        };
-        (
-            StatusCode::OK,
-            AppendHeaders([(SET_COOKIE, format!("session_id={}", session_id))]),
-        )
+    ) -> AppResult<(StatusCode, SetCookies)> {
+        let Some(session) = Session::current(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+
+        state
+            .sessions_collection
+            .delete_one(bson::doc! { "_id": session.id }, None)
+            .await?;
+
+        Ok((StatusCode::NO_CONTENT, state.cookies.sign_out()))
+    }
+
+    /// Ends every session of the signed in account, on all devices.
+    pub(crate) async fn sign_out_everywhere(
+        TypedHeader(cookie): TypedHeader<Cookie>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, SetCookies)> {
+        let Some(session) = Session::current(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+
+        Session::revoke_others(&state, session.auth_id, None).await?;
+
+        Ok((StatusCode::NO_CONTENT, state.cookies.sign_out()))
     }
 }
 
 #[async_trait]
-impl Crud<SignInAuth, Auth> for Auth {
+impl Crud<SignInAuth, SignInAuth> for Auth {
+    type Filter = NoFilter;
+    type View = AuthView;
+
     async fn create(
-        TypedHeader(_): TypedHeader<Cookie>,
+        _: Credentials,
         State(state): State<AppState>,
         Json(json): Json<SignInAuth>,
-    ) -> (StatusCode, Json<Option<Auth>>) {
+    ) -> AppResult<(StatusCode, Json<AuthView>)> {
+        let email_normalized = Auth::validate_credentials(&state, &json)?;
+        if Auth::find_by_email(&state, &email_normalized).await?.is_some() {
+            return Err(AppError::Conflict(String::from("An account with this email already exists.")));
+        }
+
         let now = Utc::now();
-        let password_hash =
-            bcrypt::hash(json.password, DEFAULT_COST).expect("Failed hashing password");
+        let password_hash = state.passwords.hash(json.password).await?;
         let mut auth = Auth {
             id: None,
-            email: json.email,
+            email: json.email.trim().to_string(),
+            email_normalized,
             password_hash,
+            email_verified_at: None,
+            verification_nonce: None,
+            totp_secret: None,
+            totp_enabled: false,
+            totp_last_step: None,
+            recovery_codes: vec![],
+            oidc_subject: None,
             created_at: Some(now),
             updated_at: Some(now),
         };
 
-        let query = state.auths_collection.insert_one(&auth, None).await;
+        let document = state.auths_collection.insert_one(&auth, None).await?;
+        auth.id = document.inserted_id.as_object_id();
 
-        match query {
-            Ok(document) => {
-                let id = document.inserted_id;
-                auth.id = id.as_object_id();
-                (StatusCode::CREATED, Json(Some(auth)))
-            }
-            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Some(auth))),
+        // the account exists either way, a lost mail can be sent again
+        if let Err(error) = Auth::send_verification(&state, &auth).await {
+            eprintln!("Failed to send verification mail: {error:?}");
         }
+
+        Ok((StatusCode::CREATED, Json(AuthView::from(auth))))
     }
 
     async fn read_all(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
+        params: ListParams<NoFilter>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Vec<Auth>>>) {
-        let user = Session::user(&cookie, &state).await;
-        let Ok(user) = user else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<AuthView>>)> {
+        Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
 
-        if user.role != Role::Developer {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        }
-
-        let auths_cursor_query = state.auths_collection.find(None, None).await;
-        let Ok(mut auths_cursor) = auths_cursor_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-
-        let mut auths: Vec<Auth> = vec![];
-
-        while let Some(auth_result) = auths_cursor.next().await {
-            if let Ok(auth) = auth_result {
-                auths.push(auth);
-            }
-        }
+        let page = params.find_page(&state.auths_collection, bson::doc! {}).await?;
 
-        (StatusCode::FOUND, Json(Some(auths)))
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(AuthView::from))))
     }
 
     async fn read(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Auth>>) {
-        let user = Session::user(&cookie, &state).await;
-        let Ok(user) = user else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        if user.role != Role::Developer {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        }
+    ) -> AppResult<(StatusCode, Json<AuthView>)> {
+        Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
 
-        let auth_query = state
+        let auth = state
             .auths_collection
             .find_one(bson::doc! { "_id": id }, None)
-            .await;
-        let Ok(auth) = auth_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::NotFound) };
 
-        (StatusCode::FOUND, Json(Some(auth)))
+        Ok((StatusCode::FOUND, Json(AuthView::from(auth))))
     }
 
     async fn update(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
-        Json(json): Json<Auth>,
-    ) -> (StatusCode, Json<Option<Auth>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        let user = Session::user(&cookie, &state).await;
-        let Ok(user) = user else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        if auth.id != Some(id) || user.role != Role::Developer {
-            return (StatusCode::UNAUTHORIZED, Json(None));
+        Json(json): Json<SignInAuth>,
+    ) -> AppResult<(StatusCode, Json<AuthView>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if auth.id != Some(id) {
+            let manager = Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
+            manager.role.ensure_manages(User::role_of(&state, id).await?)?;
         }
 
-        let hashed = hash(json.password_hash, DEFAULT_COST).expect("Failed to hash password");
-        let auth_query = state
+        let email_normalized = Auth::validate_credentials(&state, &json)?;
+        let taken = Auth::find_by_email(&state, &email_normalized).await?;
+        if taken.is_some_and(|taken| taken.id != Some(id)) {
+            return Err(AppError::Conflict(String::from("An account with this email already exists.")));
+        }
+
+        let previous = state
             .auths_collection
-            .find_one_and_update(
-                bson::doc! { "_id": id },
-                bson::doc! { "$set": { "email": json.email, "password": hashed } },
-                None,
-            )
-            .await;
-        let Ok(auth) = auth_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+            .find_one(bson::doc! { "_id": id }, None)
+            .await?;
+        let Some(previous) = previous else { return Err(AppError::NotFound) };
+        let email_changed = previous.email_normalized != email_normalized;
 
-        (StatusCode::OK, Json(Some(auth)))
+        let hashed = state.passwords.hash(json.password).await?;
+        let mut update = bson::doc! { "$set": {
+            "email": json.email.trim(),
+            "email_normalized": email_normalized,
+            "password_hash": hashed,
+            "updatedAt": bson::to_bson(&Utc::now())?,
+        } };
+        // a new address has to be verified again
+        if email_changed {
+            update.insert("$unset", bson::doc! { "email_verified_at": "", "verification_nonce": "" });
+        }
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let auth = state
+            .auths_collection
+            .find_one_and_update(bson::doc! { "_id": id }, update, options)
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::NotFound) };
+
+        // whoever held the old credentials loses access, except on this device
+        let current = credentials.session(&state).await?;
+        Auth::revoke_credentials(&state, id, current.and_then(|session| session.id)).await?;
+
+        if email_changed {
+            if let Err(error) = Auth::send_verification(&state, &auth).await {
+                eprintln!("Failed to send verification mail: {error:?}");
+            }
+        }
+
+        Ok((StatusCode::OK, Json(AuthView::from(auth))))
     }
 
     async fn delete(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Auth>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        let user = Session::user(&cookie, &state).await;
-        let Ok(user) = user else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        if auth.id != Some(id) || user.role != Role::Developer {
-            return (StatusCode::UNAUTHORIZED, Json(None));
+    ) -> AppResult<(StatusCode, Json<AuthView>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if auth.id != Some(id) {
+            let manager = Session::authorize(&credentials, &state, Scope::Account, Permission::UsersManage).await?;
+            manager.role.ensure_manages(User::role_of(&state, id).await?)?;
         }
 
-        let auth_query = state
+        let auth = state
             .auths_collection
             .find_one_and_delete(bson::doc! { "_id": id }, None)
-            .await;
-        let Ok(auth) = auth_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::NotFound) };
 
-        (StatusCode::OK, Json(Some(auth)))
+        Ok((StatusCode::OK, Json(AuthView::from(auth))))
     }
 }
@@ -0,0 +1,465 @@
+use super::policy::normalize_email;
+use super::{Auth, SignInThrottle};
+use crate::cookie::{SetCookie, SetCookies};
+use crate::error::{stored_id, AppError, AppResult};
+use crate::session::{ClientInfo, Session};
+use crate::utils::token;
+use crate::AppState;
+
+use axum::extract::{Query, State};
+use axum::headers::Cookie;
+use axum::response::Redirect;
+use axum::TypedHeader;
+use chrono::{Duration, Utc};
+use mongodb::bson;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use openidconnect::core::{CoreAuthenticationFlow, CoreClient, CoreProviderMetadata};
+use openidconnect::reqwest::async_http_client;
+use openidconnect::url::Url;
+use openidconnect::{
+    AuthorizationCode, ClientId, ClientSecret, CsrfToken, IssuerUrl, Nonce, PkceCodeChallenge,
+    PkceCodeVerifier, RedirectUrl, Scope, TokenResponse,
+};
+use serde::Deserialize;
+
+/// Minutes a sign in may spend at the provider.
+const LOGIN_LIFETIME: i64 = 10;
+
+/// Sign in through an OpenID Connect provider, enabled by setting `BLOG_OIDC_ISSUER`
+/// and `BLOG_OIDC_CLIENT_ID`. `BLOG_OIDC_CLIENT_SECRET` is for confidential clients,
+/// and `BLOG_OIDC_REDIRECT_URL` defaults to `{BLOG_URL}/api/auth/oidc/callback`.
+#[derive(Clone, Debug)]
+pub(crate) struct OidcConfig {
+    pub issuer: IssuerUrl,
+    pub client_id: ClientId,
+    pub client_secret: Option<ClientSecret>,
+    pub redirect_url: RedirectUrl,
+}
+
+impl OidcConfig {
+    pub(crate) fn from_env(site_url: &str) -> Option<Self> {
+        let issuer = std::env::var("BLOG_OIDC_ISSUER").ok()?;
+        let client_id = std::env::var("BLOG_OIDC_CLIENT_ID").ok()?;
+        let redirect_url = std::env::var("BLOG_OIDC_REDIRECT_URL")
+            .unwrap_or(format!("{site_url}/api/auth/oidc/callback"));
+
+        let urls = IssuerUrl::new(issuer).and_then(|issuer| Ok((issuer, RedirectUrl::new(redirect_url)?)));
+        let (issuer, redirect_url) = match urls {
+            Ok(urls) => urls,
+            Err(error) => {
+                eprintln!("Invalid OIDC configuration ({error}), signing in through OIDC is disabled.");
+                return None;
+            }
+        };
+
+        Some(OidcConfig {
+            issuer,
+            client_id: ClientId::new(client_id),
+            client_secret: std::env::var("BLOG_OIDC_CLIENT_SECRET").ok().map(ClientSecret::new),
+            redirect_url,
+        })
+    }
+
+    /// Discovers the provider's endpoints and signing keys, for every sign in so
+    /// rotated keys are picked up.
+    async fn client(&self) -> AppResult<CoreClient> {
+        let metadata = CoreProviderMetadata::discover_async(self.issuer.clone(), async_http_client)
+            .await
+            .map_err(|error| AppError::Internal(format!("OIDC discovery failed: {error}")))?;
+
+        Ok(CoreClient::from_provider_metadata(metadata, self.client_id.clone(), self.client_secret.clone())
+            .set_redirect_uri(self.redirect_url.clone()))
+    }
+}
+
+/// Where the provider sends the browser back to, with either a code or an error.
+#[derive(Deserialize)]
+pub(crate) struct OidcCallback {
+    pub code: Option<String>,
+    pub state: Option<String>,
+    pub error: Option<String>,
+}
+
+/// Who the provider vouched for, with a verified email.
+#[derive(Debug, PartialEq)]
+struct OidcIdentity {
+    /// `<issuer>|<sub>`, stable even when the email changes.
+    subject: String,
+    email: String,
+}
+
+impl OidcConfig {
+    /// The provider's authorization URL, and the signed value of the `oidc_login`
+    /// cookie carrying the state, nonce and PKCE verifier to the callback.
+    async fn start(&self, secret: &[u8]) -> AppResult<(Url, String)> {
+        let client = self.client().await?;
+
+        let (pkce_challenge, pkce_verifier) = PkceCodeChallenge::new_random_sha256();
+        let (url, csrf_state, nonce) = client
+            .authorize_url(CoreAuthenticationFlow::AuthorizationCode, CsrfToken::new_random, Nonce::new_random)
+            .add_scope(Scope::new(String::from("email")))
+            .set_pkce_challenge(pkce_challenge)
+            .url();
+
+        let expires_at = (Utc::now() + Duration::minutes(LOGIN_LIFETIME)).timestamp();
+        let login = token::sign(
+            secret,
+            &format!("{}.{}.{}.{expires_at}", csrf_state.secret(), nonce.secret(), pkce_verifier.secret()),
+        );
+
+        Ok((url, login))
+    }
+
+    /// Checks the callback against the `oidc_login` cookie, trades the code for an ID
+    /// token and validates it against the provider's keys and the nonce. Fails with
+    /// `AppError::Unauthorized` when the provider or its token cannot be trusted.
+    async fn finish(&self, secret: &[u8], login: Option<&str>, query: OidcCallback) -> AppResult<OidcIdentity> {
+        let invalid = || AppError::BadRequest(String::from("The sign in expired or was started elsewhere, try again."));
+
+        if let Some(error) = query.error {
+            return Err(AppError::BadRequest(format!("The identity provider refused the sign in: {error}.")));
+        }
+        let Some(login) = login.and_then(|login| token::verify(secret, login)) else { return Err(invalid()) };
+        let mut parts = login.split('.');
+        let (Some(csrf_state), Some(nonce), Some(pkce_verifier), Some(expires_at)) =
+            (parts.next(), parts.next(), parts.next(), parts.next()) else { return Err(invalid()) };
+        let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+        if expires_at < Utc::now().timestamp() {
+            return Err(invalid());
+        }
+        let (Some(code), Some(returned_state)) = (query.code, query.state) else { return Err(invalid()) };
+        if !token::constant_time_eq(csrf_state, &returned_state) {
+            return Err(invalid());
+        }
+
+        let client = self.client().await?;
+        let response = client
+            .exchange_code(AuthorizationCode::new(code))
+            .set_pkce_verifier(PkceCodeVerifier::new(pkce_verifier.to_string()))
+            .request_async(async_http_client)
+            .await;
+        let response = match response {
+            Ok(response) => response,
+            Err(error) => {
+                eprintln!("The OIDC code exchange failed: {error}");
+                return Err(AppError::Unauthorized);
+            }
+        };
+        let Some(id_token) = response.id_token() else { return Err(AppError::Unauthorized) };
+        let claims = match id_token.claims(&client.id_token_verifier(), &Nonce::new(nonce.to_string())) {
+            Ok(claims) => claims,
+            Err(error) => {
+                eprintln!("Rejected an OIDC ID token: {error}");
+                return Err(AppError::Unauthorized);
+            }
+        };
+
+        let Some(email) = claims.email() else { return Err(AppError::BadRequest(String::from("The identity provider did not share an email address."))) };
+        if claims.email_verified() != Some(true) {
+            return Err(AppError::EmailNotVerified);
+        }
+
+        Ok(OidcIdentity {
+            subject: format!("{}|{}", claims.issuer().as_str(), claims.subject().as_str()),
+            email: email.to_string(),
+        })
+    }
+}
+
+impl Auth {
+    /// Redirects to the provider. The state, nonce and PKCE verifier go along in a
+    /// signed cookie, so the callback only completes in the browser that started it.
+    pub(crate) async fn oidc_login(State(state): State<AppState>) -> AppResult<(SetCookie, Redirect)> {
+        let Some(oidc) = &state.oidc else { return Err(AppError::NotFound) };
+        let (url, login) = oidc.start(&state.secret).await?;
+
+        Ok((state.cookies.oidc_login(&login, LOGIN_LIFETIME * 60), Redirect::to(url.as_str())))
+    }
+
+    /// Finishes signing in through the provider and signs in the account of its
+    /// verified email just like `Auth::sign_in`, throttled the same way. Until the
+    /// ID token is trusted the email is not known, so failures count for the IP.
+    pub(crate) async fn oidc_callback(
+        client: ClientInfo,
+        cookie: Option<TypedHeader<Cookie>>,
+        Query(query): Query<OidcCallback>,
+        State(state): State<AppState>,
+    ) -> AppResult<(SetCookies, SetCookie, Redirect)> {
+        let Some(oidc) = &state.oidc else { return Err(AppError::NotFound) };
+        let ip = client.ip.clone();
+        SignInThrottle::check_ip(&state, ip.as_deref()).await?;
+
+        let login = cookie.as_ref().and_then(|TypedHeader(cookie)| cookie.get("oidc_login"));
+        let identity = match oidc.finish(&state.secret, login, query).await {
+            Ok(identity) => identity,
+            Err(AppError::Unauthorized) => {
+                SignInThrottle::fail_ip(&state, ip.as_deref()).await?;
+                return Err(AppError::Unauthorized);
+            }
+            Err(error) => return Err(error),
+        };
+        SignInThrottle::check(&state, &identity.email, ip.as_deref()).await?;
+
+        let auth = Auth::link_oidc(&state, &identity.subject, &identity.email).await?;
+        let auth_id = stored_id(auth.id)?;
+        let session = Session::start(&state, auth_id, client, auth.totp_enabled).await?;
+        SignInThrottle::succeed(&state, &identity.email).await?;
+
+        Ok((
+            state.cookies.sign_in(&session.session_id),
+            state.cookies.clear_oidc_login(),
+            Redirect::to(&format!("{}/", state.site_url)),
+        ))
+    }
+
+    /// The account that signed in as `subject` before, or else the one registered
+    /// under `email`, now linked to it. Without either a new account, with no
+    /// password, is created.
+    async fn link_oidc(state: &AppState, subject: &str, email: &str) -> AppResult<Auth> {
+        let auth = state
+            .auths_collection
+            .find_one(bson::doc! { "oidc_subject": subject }, None)
+            .await?;
+        if let Some(auth) = auth {
+            return Ok(auth);
+        }
+
+        let email_normalized = normalize_email(email).map_err(|error| AppError::Validation(vec![error]))?;
+        let now = Utc::now();
+
+        let Some(auth) = Auth::find_by_email(state, &email_normalized).await? else {
+            let mut auth = Auth {
+                id: None,
+                email: email.trim().to_string(),
+                email_normalized,
+                password_hash: String::new(),
+                email_verified_at: Some(now),
+                verification_nonce: None,
+                totp_secret: None,
+                totp_enabled: false,
+                totp_last_step: None,
+                recovery_codes: vec![],
+                oidc_subject: Some(subject.to_string()),
+                created_at: Some(now),
+                updated_at: Some(now),
+            };
+            let document = state.auths_collection.insert_one(&auth, None).await?;
+            auth.id = document.inserted_id.as_object_id();
+
+            return Ok(auth);
+        };
+        let auth_id = stored_id(auth.id)?;
+
+        let mut update = bson::doc! {
+            "oidc_subject": subject,
+            "email_verified_at": bson::to_bson(&auth.email_verified_at.unwrap_or(now))?,
+            "updatedAt": bson::to_bson(&now)?,
+        };
+        // whoever registered the address without verifying it never proved owning it,
+        // so their password and sessions go
+        if !auth.is_verified() {
+            update.insert("password_hash", "");
+            Session::revoke_others(state, auth_id, None).await?;
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let auth = state
+            .auths_collection
+            .find_one_and_update(bson::doc! { "_id": auth_id }, bson::doc! { "$set": update }, options)
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::NotFound) };
+
+        Ok(auth)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::header::CONTENT_TYPE;
+    use axum::http::StatusCode;
+    use axum::response::{IntoResponse, Response};
+    use axum::routing::{get, post};
+    use axum::{Form, Router};
+    use openidconnect::core::{
+        CoreIdToken, CoreIdTokenClaims, CoreIdTokenFields, CoreJsonWebKeySet, CoreJwsSigningAlgorithm,
+        CoreResponseType, CoreRsaPrivateSigningKey, CoreSubjectIdentifierType, CoreTokenResponse, CoreTokenType,
+    };
+    use openidconnect::{
+        AccessToken, Audience, AuthUrl, EmptyAdditionalClaims, EmptyAdditionalProviderMetadata,
+        EmptyExtraTokenFields, EndUserEmail, JsonWebKeyId, JsonWebKeySetUrl, PrivateSigningKey, ResponseTypes,
+        StandardClaims, SubjectIdentifier, TokenUrl,
+    };
+    use rsa::pkcs1::{EncodeRsaPrivateKey, LineEnding};
+    use serde::Serialize;
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex, OnceLock};
+
+    const SECRET: &[u8] = b"secret";
+    const CLIENT_ID: &str = "blog";
+    const CODE: &str = "code";
+    const EMAIL: &str = "jane@example.com";
+
+    /// What the mock provider expects and puts in the ID token of the next exchange.
+    #[derive(Clone, Default)]
+    struct Grant {
+        nonce: String,
+        code_challenge: String,
+        email_verified: bool,
+    }
+
+    #[derive(Clone)]
+    struct Provider {
+        issuer: String,
+        grant: Arc<Mutex<Grant>>,
+    }
+
+    /// PKCS#1 PEM of an RSA key made once for all tests, since that takes a while.
+    fn key_pem() -> &'static str {
+        static KEY: OnceLock<String> = OnceLock::new();
+        KEY.get_or_init(|| {
+            let key = rsa::RsaPrivateKey::new(&mut rand::thread_rng(), 2048).unwrap();
+            key.to_pkcs1_pem(LineEnding::LF).unwrap().to_string()
+        })
+    }
+
+    fn signing_key() -> CoreRsaPrivateSigningKey {
+        CoreRsaPrivateSigningKey::from_pem(key_pem(), Some(JsonWebKeyId::new(String::from("test")))).unwrap()
+    }
+
+    fn json(value: &impl Serialize) -> Response {
+        ([(CONTENT_TYPE, "application/json")], serde_json::to_string(value).unwrap()).into_response()
+    }
+
+    async fn discovery(State(provider): State<Provider>) -> Response {
+        let url = |path: &str| format!("{}{path}", provider.issuer);
+        let metadata = CoreProviderMetadata::new(
+            IssuerUrl::new(provider.issuer.clone()).unwrap(),
+            AuthUrl::new(url("/authorize")).unwrap(),
+            JsonWebKeySetUrl::new(url("/jwks")).unwrap(),
+            vec![ResponseTypes::new(vec![CoreResponseType::Code])],
+            vec![CoreSubjectIdentifierType::Public],
+            vec![CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256],
+            EmptyAdditionalProviderMetadata {},
+        )
+        .set_token_endpoint(Some(TokenUrl::new(url("/token")).unwrap()));
+        json(&metadata)
+    }
+
+    async fn jwks() -> Response {
+        json(&CoreJsonWebKeySet::new(vec![signing_key().as_verification_key()]))
+    }
+
+    async fn token(State(provider): State<Provider>, Form(form): Form<HashMap<String, String>>) -> Response {
+        let grant = provider.grant.lock().unwrap().clone();
+        let verifier = PkceCodeVerifier::new(form.get("code_verifier").cloned().unwrap_or_default());
+        let challenge = PkceCodeChallenge::from_code_verifier_sha256(&verifier);
+        if form.get("code").map(String::as_str) != Some(CODE) || challenge.as_str() != grant.code_challenge {
+            let error = serde_json::json!({ "error": "invalid_grant" });
+            return (StatusCode::BAD_REQUEST, json(&error)).into_response();
+        }
+
+        let now = Utc::now();
+        let claims = CoreIdTokenClaims::new(
+            IssuerUrl::new(provider.issuer.clone()).unwrap(),
+            vec![Audience::new(String::from(CLIENT_ID))],
+            now + Duration::minutes(5),
+            now,
+            StandardClaims::new(SubjectIdentifier::new(String::from("user-1")))
+                .set_email(Some(EndUserEmail::new(String::from(EMAIL))))
+                .set_email_verified(Some(grant.email_verified)),
+            EmptyAdditionalClaims {},
+        )
+        .set_nonce(Some(Nonce::new(grant.nonce)));
+        let id_token = CoreIdToken::new(
+            claims,
+            &signing_key(),
+            CoreJwsSigningAlgorithm::RsaSsaPkcs1V15Sha256,
+            None,
+            None,
+        )
+        .unwrap();
+
+        json(&CoreTokenResponse::new(
+            AccessToken::new(String::from("access")),
+            CoreTokenType::Bearer,
+            CoreIdTokenFields::new(Some(id_token), EmptyExtraTokenFields {}),
+        ))
+    }
+
+    /// Serves a provider on a free local port, returning the config pointing at it.
+    fn serve(grant: Arc<Mutex<Grant>>) -> OidcConfig {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let issuer = format!("http://{}", listener.local_addr().unwrap());
+        let app = Router::new()
+            .route("/.well-known/openid-configuration", get(discovery))
+            .route("/jwks", get(jwks))
+            .route("/token", post(token))
+            .with_state(Provider { issuer: issuer.clone(), grant });
+        tokio::spawn(axum::Server::from_tcp(listener).unwrap().serve(app.into_make_service()));
+
+        OidcConfig {
+            issuer: IssuerUrl::new(issuer).unwrap(),
+            client_id: ClientId::new(String::from(CLIENT_ID)),
+            client_secret: None,
+            redirect_url: RedirectUrl::new(String::from("http://blog.test/api/auth/oidc/callback")).unwrap(),
+        }
+    }
+
+    /// Goes through login and callback the way a browser would, with the provider
+    /// answering `state` and signing `nonce`, the ones of the login by default.
+    async fn sign_in(grant: Grant, state: Option<&str>) -> AppResult<OidcIdentity> {
+        let grant = Arc::new(Mutex::new(grant));
+        let config = serve(grant.clone());
+
+        let (url, login) = config.start(SECRET).await?;
+        let params: HashMap<String, String> = url.query_pairs().into_owned().collect();
+        {
+            let mut grant = grant.lock().unwrap();
+            if grant.nonce.is_empty() {
+                grant.nonce = params["nonce"].clone();
+            }
+            grant.code_challenge = params["code_challenge"].clone();
+        }
+
+        let query = OidcCallback {
+            code: Some(String::from(CODE)),
+            state: Some(state.map(String::from).unwrap_or(params["state"].clone())),
+            error: None,
+        };
+        config.finish(SECRET, Some(&login), query).await
+    }
+
+    #[tokio::test]
+    async fn signs_in_with_a_verified_email() {
+        let identity = sign_in(Grant { email_verified: true, ..Grant::default() }, None).await.unwrap();
+
+        assert!(identity.subject.starts_with("http://127.0.0.1:"));
+        assert!(identity.subject.ends_with("|user-1"));
+        assert_eq!(identity.email, EMAIL);
+    }
+
+    #[tokio::test]
+    async fn rejects_an_id_token_with_another_nonce() {
+        let grant = Grant { nonce: String::from("replayed"), email_verified: true, ..Grant::default() };
+
+        assert!(matches!(sign_in(grant, None).await, Err(AppError::Unauthorized)));
+    }
+
+    #[tokio::test]
+    async fn rejects_unverified_emails() {
+        let grant = Grant { email_verified: false, ..Grant::default() };
+
+        assert!(matches!(sign_in(grant, None).await, Err(AppError::EmailNotVerified)));
+    }
+
+    #[tokio::test]
+    async fn rejects_a_callback_for_another_login() {
+        let grant = Grant { email_verified: true, ..Grant::default() };
+
+        assert!(matches!(sign_in(grant, Some("forged")).await, Err(AppError::BadRequest(_))));
+    }
+}
@@ -0,0 +1,174 @@
+use super::{Auth, RequestLimit};
+use crate::error::{AppError, AppResult};
+use crate::mail::Mail;
+use crate::session::ClientInfo;
+use crate::utils::token;
+use crate::view::Json;
+use crate::AppState;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::IndexOptions;
+use mongodb::{Collection, IndexModel};
+use nanoid::nanoid;
+use serde::{Deserialize, Serialize};
+
+/// Minutes a reset link stays usable.
+const RESET_TTL: i64 = 30;
+
+/// A pending password reset. Only the hash of the mailed token is stored, so a
+/// leaked database does not let anyone take over accounts.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct PasswordReset {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub auth_id: ObjectId,
+    pub token_hash: String,
+    /// BSON date, so the TTL index can purge unused resets.
+    pub expires_at: bson::DateTime,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RequestReset {
+    pub email: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct ConfirmReset {
+    pub token: String,
+    pub password: String,
+}
+
+impl PasswordReset {
+    pub(crate) async fn create_indexes(
+        password_resets_collection: &Collection<PasswordReset>,
+    ) -> mongodb::error::Result<()> {
+        let token_index = IndexModel::builder()
+            .keys(bson::doc! { "token_hash": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        password_resets_collection
+            .create_indexes([token_index, ttl_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Replaces any pending reset of the account registered under `email` with a new
+    /// one and mails its link.
+    async fn issue(state: &AppState, email: &str) -> AppResult<()> {
+        let Some(auth) = Auth::find_by_email(state, email).await? else { return Ok(()) };
+        let Some(auth_id) = auth.id else { return Ok(()) };
+
+        state
+            .password_resets_collection
+            .delete_many(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+
+        let reset_token = nanoid!(32);
+        let now = Utc::now();
+        let expires_at = now + Duration::minutes(RESET_TTL);
+        state
+            .password_resets_collection
+            .insert_one(
+                PasswordReset {
+                    id: None,
+                    auth_id,
+                    token_hash: token::hash(&reset_token),
+                    expires_at: bson::DateTime::from_millis(expires_at.timestamp_millis()),
+                    created_at: Some(now),
+                },
+                None,
+            )
+            .await?;
+
+        let body = format!(
+            "Someone asked to reset the password of your {} account. If it was you, open this link within {RESET_TTL} minutes:\n\n{}\n\nOtherwise you can ignore this mail.\n",
+            state.site_title,
+            state.links.reset_password(&reset_token)
+        );
+        state
+            .mailer
+            .send(Mail {
+                to: auth.email,
+                subject: format!("Reset your password for {}", state.site_title),
+                body,
+            })
+            .await
+    }
+}
+
+impl Auth {
+    /// Starts a password reset. Responds the same way, and just as fast, whether or
+    /// not an account uses `email`, so it cannot be used to find out. Limited per
+    /// email and client IP, so it cannot be used to flood an inbox either.
+    pub(crate) async fn request_password_reset(
+        client: ClientInfo,
+        State(state): State<AppState>,
+        Json(json): Json<RequestReset>,
+    ) -> AppResult<StatusCode> {
+        RequestLimit::hit(&state, "password_reset", &json.email, client.ip.as_deref()).await?;
+
+        tokio::spawn(async move {
+            if let Err(error) = PasswordReset::issue(&state, json.email.trim()).await {
+                eprintln!("Failed to issue a password reset: {error:?}");
+            }
+        });
+
+        Ok(StatusCode::ACCEPTED)
+    }
+
+    /// Sets a new password with a mailed reset token, then signs the account out
+    /// everywhere and drops its API tokens and sign in links.
+    pub(crate) async fn confirm_password_reset(
+        State(state): State<AppState>,
+        Json(json): Json<ConfirmReset>,
+    ) -> AppResult<StatusCode> {
+        if let Some(error) = state.password_policy.check(&json.password) {
+            return Err(AppError::Validation(vec![error]));
+        }
+
+        let reset = state
+            .password_resets_collection
+            .find_one_and_delete(bson::doc! { "token_hash": token::hash(&json.token) }, None)
+            .await?;
+        let invalid = || AppError::BadRequest(String::from("The reset link is invalid or expired."));
+        let Some(reset) = reset else { return Err(invalid()) };
+        if reset.expires_at.timestamp_millis() < Utc::now().timestamp_millis() {
+            return Err(invalid());
+        }
+
+        let password_hash = state.passwords.hash(json.password).await?;
+        let result = state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": reset.auth_id },
+                bson::doc! { "$set": {
+                    "password_hash": password_hash,
+                    "updatedAt": bson::to_bson(&Utc::now())?,
+                } },
+                None,
+            )
+            .await?;
+        if result.matched_count == 0 {
+            return Err(invalid());
+        }
+
+        Auth::revoke_credentials(&state, reset.auth_id, None).await?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
@@ -0,0 +1,305 @@
+use super::{Auth, SignInAuth};
+use crate::error::{AppError, AppResult, FieldError};
+use crate::AppState;
+
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+use mongodb::options::{FindOptions, IndexOptions, UpdateModifications};
+use mongodb::{Collection, IndexModel};
+use sha1::{Digest, Sha1};
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+const MAX_EMAIL_LENGTH: usize = 254;
+const MAX_LOCAL_PART_LENGTH: usize = 64;
+const MAX_LABEL_LENGTH: usize = 63;
+/// Characters RFC 5322 allows unquoted in the local part, besides letters and digits.
+const LOCAL_PART_SYMBOLS: &str = "!#$%&'*+-/=?^_`{|}~.";
+const DEFAULT_MIN_LENGTH: usize = 8;
+/// Room for any passphrase, while keeping hashing cheap.
+const DEFAULT_MAX_LENGTH: usize = 128;
+
+/// What passwords have to look like. Read from `BLOG_PASSWORD_MIN_LENGTH`,
+/// `BLOG_PASSWORD_MAX_LENGTH` and `BLOG_BREACHED_PASSWORDS`, the path of a file with
+/// the SHA-1 of a known breached password on each line, like the Have I Been Pwned
+/// downloads. A `:count` after the hash is ignored.
+#[derive(Clone)]
+pub(crate) struct PasswordPolicy {
+    pub min_length: usize,
+    pub max_length: usize,
+    /// Uppercase hex SHA-1 hashes.
+    breached: Arc<HashSet<String>>,
+}
+
+impl PasswordPolicy {
+    pub(crate) fn from_env() -> Self {
+        let number = |name: &str, default: usize| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        let breached = match std::env::var("BLOG_BREACHED_PASSWORDS") {
+            Ok(path) => match std::fs::read_to_string(&path) {
+                Ok(list) => list
+                    .lines()
+                    .filter_map(|line| line.split(':').next())
+                    .map(|hash| hash.trim().to_uppercase())
+                    .filter(|hash| !hash.is_empty())
+                    .collect(),
+                Err(error) => {
+                    eprintln!("Failed to read the breached passwords at {path}: {error}");
+                    HashSet::new()
+                }
+            },
+            Err(_) => HashSet::new(),
+        };
+
+        PasswordPolicy {
+            min_length: number("BLOG_PASSWORD_MIN_LENGTH", DEFAULT_MIN_LENGTH),
+            max_length: number("BLOG_PASSWORD_MAX_LENGTH", DEFAULT_MAX_LENGTH),
+            breached: Arc::new(breached),
+        }
+    }
+
+    /// What is wrong with `password`, if anything.
+    pub(crate) fn check(&self, password: &str) -> Option<FieldError> {
+        let length = password.chars().count();
+        if length < self.min_length {
+            return Some(FieldError::new("password", format!("must be at least {} characters long", self.min_length)));
+        }
+        if length > self.max_length {
+            return Some(FieldError::new("password", format!("must be at most {} characters long", self.max_length)));
+        }
+
+        let hash = format!("{:X}", Sha1::digest(password.as_bytes()));
+        if self.breached.contains(&hash) {
+            return Some(FieldError::new("password", "appears in a known data breach, choose another one"));
+        }
+
+        None
+    }
+}
+
+/// Trims and lowercases `email`, failing when it is not shaped like an address.
+/// Whether it can receive mail is for `Auth::send_verification` to find out.
+pub(crate) fn normalize_email(email: &str) -> Result<String, FieldError> {
+    let email = email.trim().to_lowercase();
+    let invalid = || FieldError::new("email", "is not a valid email address");
+    if email.len() > MAX_EMAIL_LENGTH {
+        return Err(invalid());
+    }
+    let Some((local_part, domain)) = email.rsplit_once('@') else { return Err(invalid()) };
+
+    let local_part_valid = !local_part.is_empty()
+        && local_part.len() <= MAX_LOCAL_PART_LENGTH
+        && !local_part.starts_with('.')
+        && !local_part.ends_with('.')
+        && !local_part.contains("..")
+        && local_part
+            .chars()
+            .all(|character| character.is_alphanumeric() || LOCAL_PART_SYMBOLS.contains(character));
+    let labels: Vec<&str> = domain.split('.').collect();
+    let domain_valid = labels.len() >= 2
+        && labels.iter().all(|label| {
+            !label.is_empty()
+                && label.len() <= MAX_LABEL_LENGTH
+                && !label.starts_with('-')
+                && !label.ends_with('-')
+                && label.chars().all(|character| character.is_alphanumeric() || character == '-')
+        });
+
+    if !local_part_valid || !domain_valid {
+        return Err(invalid());
+    }
+    Ok(email)
+}
+
+/// The normalized emails shared by more than one account, with those accounts.
+fn duplicate_emails(accounts: Vec<(ObjectId, String)>) -> BTreeMap<String, Vec<ObjectId>> {
+    let mut by_email: BTreeMap<String, Vec<ObjectId>> = BTreeMap::new();
+    for (id, email) in accounts {
+        by_email.entry(email).or_default().push(id);
+    }
+    by_email.retain(|_, ids| ids.len() > 1);
+    by_email
+}
+
+impl Auth {
+    pub(crate) async fn create_indexes(auths_collection: &Collection<Auth>) -> mongodb::error::Result<()> {
+        // accounts from before emails were normalized get their normalized email first
+        auths_collection
+            .update_many(
+                bson::doc! { "email_normalized": { "$exists": false } },
+                UpdateModifications::Pipeline(vec![bson::doc! {
+                    "$set": { "email_normalized": { "$toLower": { "$trim": { "input": "$email" } } } }
+                }]),
+                None,
+            )
+            .await?;
+
+        let oidc_index = IndexModel::builder()
+            .keys(bson::doc! { "oidc_subject": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(bson::doc! { "oidc_subject": { "$type": "string" } })
+                    .build(),
+            )
+            .build();
+        auths_collection.create_index(oidc_index, None).await?;
+
+        // accounts like `A@x.com` and `a@x.com` predate normalizing, the unique index
+        // waits until an operator has merged or renamed them
+        let options = FindOptions::builder()
+            .projection(bson::doc! { "_id": 1, "email_normalized": 1 })
+            .build();
+        let mut cursor = auths_collection
+            .clone_with_type::<Document>()
+            .find(None, options)
+            .await?;
+        let mut accounts = vec![];
+        while let Some(account) = cursor.next().await {
+            let account = account?;
+            if let (Ok(id), Ok(email)) = (account.get_object_id("_id"), account.get_str("email_normalized")) {
+                accounts.push((id, email.to_string()));
+            }
+        }
+
+        let duplicates = duplicate_emails(accounts);
+        if !duplicates.is_empty() {
+            for (email, ids) in &duplicates {
+                let ids: Vec<String> = ids.iter().map(|id| id.to_hex()).collect();
+                eprintln!("Accounts {} all use the email {email}.", ids.join(", "));
+            }
+            eprintln!("Emails are not unique until these accounts are merged or changed, then restart.");
+            return Ok(());
+        }
+
+        let email_index = IndexModel::builder()
+            .keys(bson::doc! { "email_normalized": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        auths_collection.create_index(email_index, None).await?;
+
+        Ok(())
+    }
+
+    /// Finds the account registered under `email`, whatever its case.
+    pub(crate) async fn find_by_email(state: &AppState, email: &str) -> AppResult<Option<Auth>> {
+        let auth = state
+            .auths_collection
+            .find_one(bson::doc! { "email_normalized": email.trim().to_lowercase() }, None)
+            .await?;
+
+        Ok(auth)
+    }
+
+    /// Checks the email and password of `json` against the policies, reporting every
+    /// problem at once. Returns the normalized email.
+    pub(crate) fn validate_credentials(state: &AppState, json: &SignInAuth) -> AppResult<String> {
+        let email = normalize_email(&json.email);
+        let mut errors: Vec<FieldError> = state.password_policy.check(&json.password).into_iter().collect();
+
+        match email {
+            Ok(email) if errors.is_empty() => Ok(email),
+            Ok(_) => Err(AppError::Validation(errors)),
+            Err(error) => {
+                errors.insert(0, error);
+                Err(AppError::Validation(errors))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(breached: &[&str]) -> PasswordPolicy {
+        let breached = breached
+            .iter()
+            .map(|password| format!("{:X}", Sha1::digest(password.as_bytes())))
+            .collect();
+        PasswordPolicy {
+            min_length: 8,
+            max_length: 16,
+            breached: Arc::new(breached),
+        }
+    }
+
+    #[test]
+    fn duplicate_emails_groups_accounts_sharing_an_email() {
+        let (first, second, third) = (ObjectId::new(), ObjectId::new(), ObjectId::new());
+        let duplicates = duplicate_emails(vec![
+            (first, String::from("a@x.com")),
+            (second, String::from("b@x.com")),
+            (third, String::from("a@x.com")),
+        ]);
+
+        assert_eq!(duplicates.len(), 1);
+        assert_eq!(duplicates["a@x.com"], [first, third]);
+    }
+
+    #[test]
+    fn duplicate_emails_is_empty_for_unique_emails() {
+        let accounts = vec![(ObjectId::new(), String::from("a@x.com")), (ObjectId::new(), String::from("b@x.com"))];
+        assert!(duplicate_emails(accounts).is_empty());
+    }
+
+    #[test]
+    fn normalize_email_trims_and_lowercases() {
+        assert_eq!(normalize_email("  Jane.Doe+blog@Example.COM ").unwrap(), "jane.doe+blog@example.com");
+    }
+
+    #[test]
+    fn normalize_email_rejects_malformed_addresses() {
+        for email in [
+            "",
+            "jane",
+            "@example.com",
+            "jane@",
+            "jane@localhost",
+            ".jane@example.com",
+            "jane.@example.com",
+            "ja..ne@example.com",
+            "ja ne@example.com",
+            "jane@-example.com",
+            "jane@example..com",
+        ] {
+            assert!(normalize_email(email).is_err(), "{email} should be rejected");
+        }
+    }
+
+    #[test]
+    fn normalize_email_rejects_overlong_parts() {
+        let local_part = "a".repeat(MAX_LOCAL_PART_LENGTH + 1);
+        let label = "a".repeat(MAX_LABEL_LENGTH + 1);
+
+        assert!(normalize_email(&format!("{local_part}@example.com")).is_err());
+        assert!(normalize_email(&format!("jane@{label}.com")).is_err());
+    }
+
+    #[test]
+    fn check_enforces_the_length_in_characters() {
+        let policy = policy(&[]);
+
+        assert!(policy.check("short").is_some());
+        assert!(policy.check("long enough").is_none());
+        assert!(policy.check("ééééééééé").is_none());
+        assert!(policy.check("far too long for this policy").is_some());
+    }
+
+    #[test]
+    fn check_rejects_breached_passwords() {
+        let policy = policy(&["password1"]);
+
+        assert!(policy.check("password1").is_some());
+        assert!(policy.check("password2").is_none());
+    }
+}
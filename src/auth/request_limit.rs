@@ -0,0 +1,100 @@
+use crate::error::{AppError, AppResult};
+use crate::AppState;
+
+use chrono::{Duration, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+/// Requests per email, and per client IP, allowed within `WINDOW_MINUTES`.
+const EMAIL_LIMIT: i64 = 5;
+const IP_LIMIT: i64 = 20;
+const WINDOW_MINUTES: i64 = 60;
+
+/// How often one email or client IP asked for a mail, like a password reset. Unlike
+/// `SignInThrottle`, going over the limit only stops further mails, never sign ins,
+/// so nobody can lock someone else out by asking on their behalf.
+#[derive(Deserialize, Serialize, Debug)]
+pub(crate) struct RequestLimit {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    /// `<action>:email:<address>` or `<action>:ip:<address>`.
+    pub key: String,
+    pub count: i64,
+    /// BSON date, end of the window, after which the TTL index forgets the count.
+    pub expires_at: bson::DateTime,
+}
+
+impl RequestLimit {
+    pub(crate) async fn create_indexes(
+        request_limits_collection: &Collection<RequestLimit>,
+    ) -> mongodb::error::Result<()> {
+        let key_index = IndexModel::builder()
+            .keys(bson::doc! { "key": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        request_limits_collection
+            .create_indexes([key_index, ttl_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Counts a request to `action` for `email` from `ip`, failing with
+    /// `AppError::TooManyRequests` once either is over its limit.
+    pub(crate) async fn hit(state: &AppState, action: &str, email: &str, ip: Option<&str>) -> AppResult<()> {
+        let now = Utc::now();
+        let now_millis = bson::DateTime::from_millis(now.timestamp_millis());
+        let expires_at = bson::DateTime::from_millis((now + Duration::minutes(WINDOW_MINUTES)).timestamp_millis());
+        let options = FindOneAndUpdateOptions::builder()
+            .upsert(true)
+            .return_document(ReturnDocument::After)
+            .build();
+
+        let mut keys = vec![(format!("{action}:email:{}", email.trim().to_lowercase()), EMAIL_LIMIT)];
+        if let Some(ip) = ip {
+            keys.push((format!("{action}:ip:{ip}"), IP_LIMIT));
+        }
+
+        let mut retry_after = 0;
+        for (key, limit) in keys {
+            // the TTL monitor only runs every minute, so drop a finished window here
+            state
+                .request_limits_collection
+                .delete_one(bson::doc! { "key": &key, "expires_at": { "$lte": now_millis } }, None)
+                .await?;
+            let request_limit = state
+                .request_limits_collection
+                .find_one_and_update(
+                    bson::doc! { "key": &key },
+                    bson::doc! {
+                        "$inc": { "count": 1 },
+                        "$setOnInsert": { "expires_at": expires_at },
+                    },
+                    options.clone(),
+                )
+                .await?;
+            let Some(request_limit) = request_limit else { continue };
+
+            if request_limit.count > limit {
+                let wait = request_limit.expires_at.timestamp_millis() - now.timestamp_millis();
+                retry_after = retry_after.max((wait.max(0) as u64).div_ceil(1000));
+            }
+        }
+
+        if retry_after > 0 {
+            return Err(AppError::TooManyRequests { retry_after });
+        }
+        Ok(())
+    }
+}
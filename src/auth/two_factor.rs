@@ -0,0 +1,350 @@
+use super::{Auth, SignInThrottle};
+use crate::api_token::Scope;
+use crate::cookie::SetCookies;
+use crate::error::{stored_id, AppError, AppResult, FieldError};
+use crate::session::{ClientInfo, Credentials, Session, SessionView};
+use crate::utils::token;
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::State;
+use axum::headers::Cookie;
+use axum::http::StatusCode;
+use axum::TypedHeader;
+use chrono::Utc;
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use nanoid::nanoid;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use totp_rs::{Algorithm, Secret, TOTP};
+
+/// Seconds a TOTP code lasts, what authenticator apps assume.
+const TOTP_STEP: i64 = 30;
+/// Steps of clock drift accepted either way.
+const TOTP_SKEW: i64 = 1;
+const RECOVERY_CODES: usize = 10;
+/// Lowercase letters and digits, without the ones easily mistaken for each other.
+const RECOVERY_ALPHABET: [char; 31] = [
+    '2', '3', '4', '5', '6', '7', '8', '9', 'a', 'b', 'c', 'd', 'e', 'f', 'g', 'h', 'j', 'k',
+    'm', 'n', 'p', 'q', 'r', 's', 't', 'u', 'v', 'w', 'x', 'y', 'z',
+];
+
+/// What an authenticator app needs, shown once when enrolling.
+#[derive(Serialize, Debug)]
+pub(crate) struct TwoFactorSetup {
+    /// Base32 secret, for typing in by hand.
+    pub secret: String,
+    /// `otpauth://` URI, usually shown as a QR code.
+    pub otpauth_uri: String,
+}
+
+impl View for TwoFactorSetup {}
+
+/// Recovery codes in plain text. Only their hashes are stored, so this is the only
+/// time they can be seen.
+#[derive(Serialize, Debug)]
+pub(crate) struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+impl View for RecoveryCodes {}
+
+#[derive(Deserialize)]
+pub(crate) struct TwoFactorCode {
+    /// A TOTP code, or one of the recovery codes.
+    pub code: String,
+}
+
+fn invalid_code() -> AppError {
+    AppError::Validation(vec![FieldError::new("code", "is invalid or expired")])
+}
+
+/// Recovery codes are shown as `xxxxx-xxxxx`, but accepted without the dash and in
+/// any case.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|character| character.is_alphanumeric())
+        .map(|character| character.to_ascii_lowercase())
+        .collect()
+}
+
+/// Time step of `code` among those around `now`, in seconds, skipping `last_step`
+/// and those before it, since a code works only once.
+fn matching_step(totp: &TOTP, code: &str, now: i64, last_step: Option<i64>) -> Option<i64> {
+    let now = now / TOTP_STEP;
+    (now - TOTP_SKEW..=now + TOTP_SKEW)
+        .filter(|step| Some(*step) > last_step)
+        .find(|step| token::constant_time_eq(&totp.generate((step * TOTP_STEP) as u64), code.trim()))
+}
+
+/// New recovery codes, with the hashes to store for them.
+fn recovery_codes() -> (Vec<String>, Vec<String>) {
+    (0..RECOVERY_CODES)
+        .map(|_| {
+            let code = nanoid!(10, &RECOVERY_ALPHABET);
+            (format!("{}-{}", &code[..5], &code[5..]), token::hash(&code))
+        })
+        .unzip()
+}
+
+impl Auth {
+    fn totp(&self, state: &AppState, secret: &str) -> AppResult<TOTP> {
+        let secret = Secret::Encoded(secret.to_string())
+            .to_bytes()
+            .map_err(|error| AppError::Internal(error.to_string()))?;
+
+        // both end up in the otpauth URI label, where ':' is the separator
+        TOTP::new(
+            Algorithm::SHA1,
+            6,
+            TOTP_SKEW as u8,
+            TOTP_STEP as u64,
+            secret,
+            Some(state.site_title.replace(':', "")),
+            self.email.replace(':', ""),
+        )
+        .map_err(|error| AppError::Internal(error.to_string()))
+    }
+
+    /// Time step of `code` if it is a current TOTP code that was not used before.
+    fn totp_step(&self, state: &AppState, code: &str) -> AppResult<Option<i64>> {
+        let Some(secret) = &self.totp_secret else { return Ok(None) };
+        let totp = self.totp(state, secret)?;
+
+        Ok(matching_step(&totp, code, Utc::now().timestamp(), self.totp_last_step))
+    }
+
+    /// Marks `step` as used, unless a concurrent request got to it or a later one first.
+    async fn use_totp_step(state: &AppState, auth_id: ObjectId, step: i64) -> AppResult<bool> {
+        let result = state
+            .auths_collection
+            .update_one(
+                bson::doc! {
+                    "_id": auth_id,
+                    "$or": [
+                        { "totp_last_step": { "$exists": false } },
+                        { "totp_last_step": { "$lt": step } },
+                    ],
+                },
+                bson::doc! { "$set": { "totp_last_step": step } },
+                None,
+            )
+            .await?;
+
+        Ok(result.modified_count == 1)
+    }
+
+    /// Checks `code` as the second factor, either a TOTP code or a recovery code.
+    /// Either way it is used up.
+    async fn check_second_factor(&self, state: &AppState, code: &str) -> AppResult<bool> {
+        let auth_id = stored_id(self.id)?;
+        if let Some(step) = self.totp_step(state, code)? {
+            return Auth::use_totp_step(state, auth_id, step).await;
+        }
+
+        let hash = token::hash(&normalize_recovery_code(code));
+        let result = state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": auth_id, "recovery_codes": &hash },
+                bson::doc! { "$pull": { "recovery_codes": &hash } },
+                None,
+            )
+            .await?;
+
+        Ok(result.modified_count == 1)
+    }
+
+    /// Generates a TOTP secret for the signed in account. It is not asked for at sign
+    /// in until `Auth::confirm_two_factor` proves the authenticator app has it, so
+    /// enrolling again just replaces it.
+    pub(crate) async fn enroll_two_factor(
+        credentials: Credentials,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<TwoFactorSetup>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if auth.totp_enabled {
+            return Err(AppError::Conflict(String::from("Two factor authentication is already enabled.")));
+        }
+        let auth_id = stored_id(auth.id)?;
+
+        let secret = Secret::Raw(rand::thread_rng().gen::<[u8; 20]>().to_vec())
+            .to_encoded()
+            .to_string();
+        state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": auth_id },
+                bson::doc! { "$set": {
+                    "totp_secret": &secret,
+                    "updatedAt": bson::to_bson(&Utc::now())?,
+                } },
+                None,
+            )
+            .await?;
+
+        let otpauth_uri = auth.totp(&state, &secret)?.get_url();
+
+        Ok((StatusCode::OK, Json(TwoFactorSetup { secret, otpauth_uri })))
+    }
+
+    /// Turns on two factor authentication with a first code from the enrolled secret,
+    /// and hands out the recovery codes. Other sessions, signed in with just the
+    /// password, end.
+    pub(crate) async fn confirm_two_factor(
+        credentials: Credentials,
+        State(state): State<AppState>,
+        Json(json): Json<TwoFactorCode>,
+    ) -> AppResult<(StatusCode, Json<RecoveryCodes>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if auth.totp_enabled {
+            return Err(AppError::Conflict(String::from("Two factor authentication is already enabled.")));
+        }
+        if auth.totp_secret.is_none() {
+            return Err(AppError::BadRequest(String::from("Enroll before confirming two factor authentication.")));
+        }
+        let auth_id = stored_id(auth.id)?;
+
+        let Some(step) = auth.totp_step(&state, &json.code)? else { return Err(invalid_code()) };
+
+        let (codes, hashes) = recovery_codes();
+        let result = state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": auth_id, "totp_enabled": { "$ne": true } },
+                bson::doc! { "$set": {
+                    "totp_enabled": true,
+                    "totp_last_step": step,
+                    "recovery_codes": hashes,
+                    "updatedAt": bson::to_bson(&Utc::now())?,
+                } },
+                None,
+            )
+            .await?;
+        if result.modified_count == 0 {
+            return Err(AppError::Conflict(String::from("Two factor authentication is already enabled.")));
+        }
+
+        let current = credentials.session(&state).await?;
+        Session::revoke_others(&state, auth_id, current.and_then(|session| session.id)).await?;
+
+        Ok((StatusCode::OK, Json(RecoveryCodes { recovery_codes: codes })))
+    }
+
+    /// Second step of `Auth::sign_in` for accounts with two factor authentication,
+    /// turning the pending session into a full one. Wrong codes count against the
+    /// same limits as wrong passwords.
+    pub(crate) async fn verify_two_factor(
+        client: ClientInfo,
+        TypedHeader(cookie): TypedHeader<Cookie>,
+        State(state): State<AppState>,
+        Json(json): Json<TwoFactorCode>,
+    ) -> AppResult<(StatusCode, SetCookies, Json<SessionView>)> {
+        let Some(mut session) = Session::pending(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+
+        let auth = state
+            .auths_collection
+            .find_one(bson::doc! { "_id": session.auth_id }, None)
+            .await?;
+        let Some(auth) = auth else { return Err(AppError::Unauthorized) };
+
+        let ip = client.ip.as_deref();
+        SignInThrottle::check(&state, &auth.email, ip).await?;
+        if !auth.check_second_factor(&state, &json.code).await? {
+            SignInThrottle::fail(&state, &auth.email, ip).await?;
+            return Err(AppError::Unauthorized);
+        }
+        SignInThrottle::succeed(&state, &auth.email).await?;
+
+        session.complete_two_factor(&state).await?;
+
+        Ok((
+            StatusCode::OK,
+            state.cookies.sign_in(&session.session_id),
+            Json(session.into_view(true)),
+        ))
+    }
+
+    /// Turns off two factor authentication, given a current code. Wrong codes are
+    /// throttled like the ones sent to `verify_two_factor`.
+    pub(crate) async fn disable_two_factor(
+        client: ClientInfo,
+        credentials: Credentials,
+        State(state): State<AppState>,
+        Json(json): Json<TwoFactorCode>,
+    ) -> AppResult<StatusCode> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if !auth.totp_enabled {
+            return Err(AppError::Conflict(String::from("Two factor authentication is not enabled.")));
+        }
+        let auth_id = stored_id(auth.id)?;
+
+        let ip = client.ip.as_deref();
+        SignInThrottle::check(&state, &auth.email, ip).await?;
+        if !auth.check_second_factor(&state, &json.code).await? {
+            SignInThrottle::fail(&state, &auth.email, ip).await?;
+            return Err(invalid_code());
+        }
+        SignInThrottle::succeed(&state, &auth.email).await?;
+
+        state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": auth_id },
+                bson::doc! {
+                    "$set": { "totp_enabled": false, "updatedAt": bson::to_bson(&Utc::now())? },
+                    "$unset": { "totp_secret": "", "totp_last_step": "", "recovery_codes": "" },
+                },
+                None,
+            )
+            .await?;
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const NOW: i64 = 1_700_000_010;
+
+    fn totp() -> TOTP {
+        let secret = Secret::Raw(b"12345678901234567890".to_vec()).to_bytes().unwrap();
+        TOTP::new(Algorithm::SHA1, 6, TOTP_SKEW as u8, TOTP_STEP as u64, secret, None, String::from("test")).unwrap()
+    }
+
+    fn code_at(step: i64) -> String {
+        totp().generate((step * TOTP_STEP) as u64)
+    }
+
+    #[test]
+    fn normalize_recovery_code_ignores_dashes_spaces_and_case() {
+        assert_eq!(normalize_recovery_code(" AbCde-F2345 "), "abcdef2345");
+        assert_eq!(normalize_recovery_code("abcde f2345"), "abcdef2345");
+    }
+
+    #[test]
+    fn matching_step_accepts_the_current_step() {
+        let step = NOW / TOTP_STEP;
+        assert_eq!(matching_step(&totp(), &code_at(step), NOW, None), Some(step));
+    }
+
+    #[test]
+    fn matching_step_allows_one_step_of_drift_either_way() {
+        let step = NOW / TOTP_STEP;
+        assert_eq!(matching_step(&totp(), &code_at(step - 1), NOW, None), Some(step - 1));
+        assert_eq!(matching_step(&totp(), &code_at(step + 1), NOW, None), Some(step + 1));
+        assert_eq!(matching_step(&totp(), &code_at(step - 2), NOW, None), None);
+        assert_eq!(matching_step(&totp(), &code_at(step + 2), NOW, None), None);
+    }
+
+    #[test]
+    fn matching_step_rejects_used_and_earlier_steps() {
+        let step = NOW / TOTP_STEP;
+        assert_eq!(matching_step(&totp(), &code_at(step), NOW, Some(step)), None);
+        assert_eq!(matching_step(&totp(), &code_at(step - 1), NOW, Some(step)), None);
+        assert_eq!(matching_step(&totp(), &code_at(step + 1), NOW, Some(step)), Some(step + 1));
+    }
+}
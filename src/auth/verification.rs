@@ -0,0 +1,144 @@
+use super::{Auth, AuthView};
+use crate::api_token::Scope;
+use crate::error::{stored_id, AppError, AppResult};
+use crate::mail::Mail;
+use crate::session::{Credentials, Session};
+use crate::utils::token;
+use crate::view::Json;
+use crate::AppState;
+
+use axum::extract::State;
+use axum::http::StatusCode;
+use chrono::{Duration, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use nanoid::nanoid;
+use serde::Deserialize;
+
+/// Hours a verification link stays usable.
+const VERIFICATION_TTL: i64 = 24;
+
+/// What accounts may not do before verifying their email. Read from the comma
+/// separated `BLOG_UNVERIFIED_RESTRICT`, e.g. `posts,comments`, which defaults to
+/// `posts`.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Restrictions {
+    pub posts: bool,
+    pub comments: bool,
+}
+
+impl Restrictions {
+    pub(crate) fn from_env() -> Self {
+        let restricted = std::env::var("BLOG_UNVERIFIED_RESTRICT").unwrap_or(String::from("posts"));
+        let restricted: Vec<&str> = restricted.split(',').map(str::trim).collect();
+
+        Restrictions {
+            posts: restricted.contains(&"posts"),
+            comments: restricted.contains(&"comments"),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+pub(crate) struct VerifyAuth {
+    pub token: String,
+}
+
+impl Auth {
+    pub(crate) fn is_verified(&self) -> bool {
+        self.email_verified_at.is_some()
+    }
+
+    /// Fails with `AppError::EmailNotVerified` when `restricted` applies to this
+    /// account, pass one of the `Restrictions` flags.
+    pub(crate) fn ensure_verified(&self, restricted: bool) -> AppResult<()> {
+        if restricted && !self.is_verified() {
+            return Err(AppError::EmailNotVerified);
+        }
+        Ok(())
+    }
+
+    /// Mails a verification link to `auth`. Issuing a new link invalidates the
+    /// previous one, since only the latest nonce is accepted.
+    pub(crate) async fn send_verification(state: &AppState, auth: &Auth) -> AppResult<()> {
+        let auth_id = stored_id(auth.id)?;
+        let nonce = nanoid!();
+        state
+            .auths_collection
+            .update_one(
+                bson::doc! { "_id": auth_id },
+                bson::doc! { "$set": { "verification_nonce": &nonce } },
+                None,
+            )
+            .await?;
+
+        let expires_at = (Utc::now() + Duration::hours(VERIFICATION_TTL)).timestamp();
+        let token = token::sign(&state.secret, &format!("{}.{expires_at}.{nonce}", auth_id.to_hex()));
+        let body = format!(
+            "Confirm your email address for {} by opening this link within {VERIFICATION_TTL} hours:\n\n{}\n",
+            state.site_title,
+            state.links.verify_email(&token)
+        );
+
+        state
+            .mailer
+            .send(Mail {
+                to: auth.email.clone(),
+                subject: format!("Verify your email for {}", state.site_title),
+                body,
+            })
+            .await
+    }
+
+    /// Consumes a verification token, marking the email of its account as verified.
+    pub(crate) async fn verify(
+        State(state): State<AppState>,
+        Json(json): Json<VerifyAuth>,
+    ) -> AppResult<(StatusCode, Json<AuthView>)> {
+        let invalid = || AppError::BadRequest(String::from("The verification link is invalid or expired."));
+
+        let payload = token::verify(&state.secret, &json.token).ok_or_else(invalid)?;
+        let mut parts = payload.split('.');
+        let (Some(auth_id), Some(expires_at), Some(nonce)) = (parts.next(), parts.next(), parts.next()) else { return Err(invalid()) };
+        let auth_id = ObjectId::parse_str(auth_id).map_err(|_| invalid())?;
+        let expires_at: i64 = expires_at.parse().map_err(|_| invalid())?;
+        if expires_at < Utc::now().timestamp() {
+            return Err(invalid());
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let now = bson::to_bson(&Utc::now())?;
+        let auth = state
+            .auths_collection
+            .find_one_and_update(
+                bson::doc! { "_id": auth_id, "verification_nonce": nonce },
+                bson::doc! {
+                    "$set": { "email_verified_at": &now, "updatedAt": &now },
+                    "$unset": { "verification_nonce": "" },
+                },
+                options,
+            )
+            .await?;
+        let Some(auth) = auth else { return Err(invalid()) };
+
+        Ok((StatusCode::OK, Json(AuthView::from(auth))))
+    }
+
+    /// Mails a fresh verification link to the signed in account.
+    pub(crate) async fn resend_verification(
+        credentials: Credentials,
+        State(state): State<AppState>,
+    ) -> AppResult<StatusCode> {
+        let auth = Session::require_auth(&credentials, &state, Scope::Account).await?;
+        if auth.is_verified() {
+            return Err(AppError::Conflict(String::from("The email is already verified.")));
+        }
+
+        Auth::send_verification(&state, &auth).await?;
+
+        Ok(StatusCode::ACCEPTED)
+    }
+}
@@ -0,0 +1,457 @@
+use crate::error::{stored_id, AppError, AppResult, FieldError};
+use crate::api_token::Scope;
+use crate::post::Post;
+use crate::session::{Credentials, Session};
+use crate::user::{Permission, User};
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, FindOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone)]
+pub(crate) struct Comment {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub post_id: ObjectId,
+    /// User that wrote the comment, unlike `Post::author_id` which holds the account.
+    pub user_id: ObjectId,
+    /// Comment this one replies to, `None` for top level comments.
+    pub parent_id: Option<ObjectId>,
+    /// Top level comment of the thread, `None` for top level comments.
+    pub root_id: Option<ObjectId>,
+    pub depth: u32,
+    pub body: String,
+    /// Deleted comments keep their place in the thread, without a body.
+    #[serde(default)]
+    pub deleted: bool,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct CreateComment {
+    pub parent_id: Option<ObjectId>,
+    pub body: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct UpdateComment {
+    pub body: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct CommentView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub post_id: ObjectId,
+    pub user_id: ObjectId,
+    pub parent_id: Option<ObjectId>,
+    pub root_id: Option<ObjectId>,
+    pub depth: u32,
+    /// Empty for deleted comments.
+    pub body: String,
+    pub deleted: bool,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl View for CommentView {}
+
+impl From<Comment> for CommentView {
+    fn from(comment: Comment) -> Self {
+        CommentView {
+            id: comment.id,
+            post_id: comment.post_id,
+            user_id: comment.user_id,
+            parent_id: comment.parent_id,
+            root_id: comment.root_id,
+            depth: comment.depth,
+            body: comment.body,
+            deleted: comment.deleted,
+            created_at: comment.created_at,
+            updated_at: comment.updated_at,
+        }
+    }
+}
+
+impl Comment {
+    pub(crate) async fn create_indexes(
+        comments_collection: &Collection<Comment>,
+    ) -> mongodb::error::Result<()> {
+        // comments used to keep their user in `author_id`
+        comments_collection
+            .update_many(
+                bson::doc! { "author_id": { "$exists": true } },
+                bson::doc! { "$rename": { "author_id": "user_id" } },
+                None,
+            )
+            .await?;
+
+        let thread_index = IndexModel::builder()
+            .keys(bson::doc! { "post_id": 1, "root_id": 1, "createdAt": 1 })
+            .build();
+        comments_collection.create_index(thread_index, None).await?;
+
+        Ok(())
+    }
+
+    /// Loads post `post_id` if the caller is allowed to read it.
+    async fn visible_post(user: Option<&User>, post_id: ObjectId, state: &AppState) -> AppResult<Post> {
+        let post = state
+            .posts_collection
+            .find_one(bson::doc! { "_id": post_id }, None)
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        if !post.is_visible_to(user.map(|user| user.auth_id)) {
+            return Err(AppError::NotFound);
+        }
+
+        Ok(post)
+    }
+
+    /// Root and depth of a comment replying to `parent`, or of a top level comment.
+    fn thread_position(parent: Option<&Comment>) -> AppResult<(Option<ObjectId>, u32)> {
+        let Some(parent) = parent else { return Ok((None, 0)) };
+        let root_id = match parent.root_id {
+            Some(root_id) => root_id,
+            None => stored_id(parent.id)?,
+        };
+
+        Ok((Some(root_id), parent.depth + 1))
+    }
+
+    /// Whether `user` wrote this comment or the post it is on, which lets them delete
+    /// it without `Permission::CommentsModerate`.
+    fn is_owned_by(&self, user: &User, post: &Post) -> bool {
+        Some(self.user_id) == user.id || post.author_id == user.auth_id
+    }
+
+    /// Update blanking a deleted comment. Replies stay attached to the thread, so only
+    /// the body goes away.
+    fn soft_delete(now: DateTime<Utc>) -> bson::ser::Result<bson::Document> {
+        Ok(bson::doc! { "$set": { "body": "", "deleted": true, "updatedAt": bson::to_bson(&now)? } })
+    }
+
+    fn validate_body(body: &str) -> AppResult<String> {
+        let body = body.trim();
+        if body.is_empty() {
+            return Err(AppError::Validation(vec![FieldError::new("body", "must not be empty")]));
+        }
+
+        Ok(body.to_string())
+    }
+
+    pub(crate) async fn create(
+        credentials: Credentials,
+        Path(post_id): Path<ObjectId>,
+        State(state): State<AppState>,
+        Json(json): Json<CreateComment>,
+    ) -> AppResult<(StatusCode, Json<CommentView>)> {
+        let (user, auth) = Session::require_account(&credentials, &state, Scope::CommentsWrite).await?;
+        auth.ensure_verified(state.restrictions.comments)?;
+        Comment::visible_post(Some(&user), post_id, &state).await?;
+        let body = Comment::validate_body(&json.body)?;
+
+        let parent = match json.parent_id {
+            Some(parent_id) => {
+                let parent = state
+                    .comments_collection
+                    .find_one(bson::doc! { "_id": parent_id, "post_id": post_id }, None)
+                    .await?;
+                let Some(parent) = parent else { return Err(AppError::NotFound) };
+                Some(parent)
+            }
+            None => None,
+        };
+        let (root_id, depth) = Comment::thread_position(parent.as_ref())?;
+
+        let now = Utc::now();
+        let mut comment = Comment {
+            id: None,
+            post_id,
+            user_id: stored_id(user.id)?,
+            parent_id: json.parent_id,
+            root_id,
+            depth,
+            body,
+            deleted: false,
+            created_at: Some(now),
+            updated_at: Some(now),
+        };
+
+        let document = state.comments_collection.insert_one(&comment, None).await?;
+        comment.id = document.inserted_id.as_object_id();
+
+        Ok((StatusCode::CREATED, Json(CommentView::from(comment))))
+    }
+
+    /// Lists the comments of a post as a flattened thread: top level comments are
+    /// paginated, and each one is followed by all of its replies in depth first order.
+    pub(crate) async fn read_all(
+        credentials: Credentials,
+        Path(post_id): Path<ObjectId>,
+        params: ListParams<NoFilter>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<CommentView>>)> {
+        let user = Session::user(&credentials, &state, Scope::CommentsRead).await?;
+        Comment::visible_post(user.as_ref(), post_id, &state).await?;
+
+        let roots = params
+            .find_page(
+                &state.comments_collection,
+                bson::doc! { "post_id": post_id, "parent_id": null },
+            )
+            .await?;
+
+        let root_ids: Vec<ObjectId> = roots.items.iter().filter_map(|root| root.id).collect();
+        let mut replies_cursor = state
+            .comments_collection
+            .find(
+                bson::doc! { "post_id": post_id, "root_id": { "$in": &root_ids } },
+                FindOptions::builder()
+                    .sort(bson::doc! { "createdAt": 1, "_id": 1 })
+                    .build(),
+            )
+            .await?;
+
+        let mut replies: Vec<Comment> = vec![];
+        while let Some(reply) = replies_cursor.next().await {
+            replies.push(reply?);
+        }
+
+        let mut items: Vec<Comment> = vec![];
+        for root in roots.items {
+            let root_id = root.id;
+            items.push(root);
+            push_replies(&mut items, &replies, root_id);
+        }
+
+        let page = Page {
+            items,
+            next: roots.next,
+        };
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(CommentView::from))))
+    }
+
+    pub(crate) async fn update(
+        credentials: Credentials,
+        Path((post_id, id)): Path<(ObjectId, ObjectId)>,
+        State(state): State<AppState>,
+        Json(json): Json<UpdateComment>,
+    ) -> AppResult<(StatusCode, Json<CommentView>)> {
+        let user = Session::require_user(&credentials, &state, Scope::CommentsWrite).await?;
+        let body = Comment::validate_body(&json.body)?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let comment = state
+            .comments_collection
+            .find_one_and_update(
+                bson::doc! { "_id": id, "post_id": post_id, "user_id": user.id, "deleted": false },
+                bson::doc! { "$set": { "body": body, "updatedAt": bson::to_bson(&Utc::now())? } },
+                options,
+            )
+            .await?;
+        let Some(comment) = comment else { return Err(AppError::NotFound) };
+
+        Ok((StatusCode::OK, Json(CommentView::from(comment))))
+    }
+
+    /// Deletes a comment on behalf of its author or a moderator, that is the post's
+    /// author or anyone allowed to moderate comments.
+    pub(crate) async fn delete(
+        credentials: Credentials,
+        Path((post_id, id)): Path<(ObjectId, ObjectId)>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<CommentView>)> {
+        let comment = state
+            .comments_collection
+            .find_one(bson::doc! { "_id": id, "post_id": post_id }, None)
+            .await?;
+        let Some(comment) = comment else { return Err(AppError::NotFound) };
+        let post = state
+            .posts_collection
+            .find_one(bson::doc! { "_id": post_id }, None)
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        Session::authorize_owner_or(
+            &credentials,
+            &state,
+            Scope::CommentsWrite,
+            |user| comment.is_owned_by(user, &post),
+            Permission::CommentsModerate,
+        )
+        .await?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let comment = state
+            .comments_collection
+            .find_one_and_update(
+                bson::doc! { "_id": id },
+                Comment::soft_delete(Utc::now())?,
+                options,
+            )
+            .await?;
+        let Some(comment) = comment else { return Err(AppError::NotFound) };
+
+        Ok((StatusCode::OK, Json(CommentView::from(comment))))
+    }
+}
+
+/// Appends the replies to `parent_id` to `items`, each followed by its own replies.
+fn push_replies(items: &mut Vec<Comment>, replies: &[Comment], parent_id: Option<ObjectId>) {
+    for reply in replies.iter().filter(|reply| reply.parent_id == parent_id) {
+        items.push(reply.clone());
+        push_replies(items, replies, reply.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::{ContentFormat, PostStatus};
+    use crate::user::Role;
+
+    fn comment(parent: Option<&Comment>) -> Comment {
+        let (root_id, depth) = Comment::thread_position(parent).unwrap();
+        Comment {
+            id: Some(ObjectId::new()),
+            post_id: ObjectId::new(),
+            user_id: ObjectId::new(),
+            parent_id: parent.and_then(|parent| parent.id),
+            root_id,
+            depth,
+            body: String::from("Hello"),
+            deleted: false,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn user(role: Role) -> User {
+        User {
+            id: Some(ObjectId::new()),
+            auth_id: ObjectId::new(),
+            display_name: String::from("Someone"),
+            role,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn post(author_id: ObjectId) -> Post {
+        Post {
+            id: Some(ObjectId::new()),
+            author_id,
+            title: String::from("Title"),
+            slug: String::from("title"),
+            previous_slugs: vec![],
+            content: String::from("Body"),
+            content_format: ContentFormat::Html,
+            content_html: String::from("Body"),
+            tags: vec![],
+            category: None,
+            status: PostStatus::Published,
+            published_at: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn may_delete(user: &User, comment: &Comment, post: &Post) -> bool {
+        user.owns_or_can(comment.is_owned_by(user, post), Permission::CommentsModerate)
+    }
+
+    #[test]
+    fn replies_go_one_level_deeper_under_the_same_root() {
+        let root = comment(None);
+        let reply = comment(Some(&root));
+        let nested = comment(Some(&reply));
+
+        assert_eq!((root.root_id, root.depth), (None, 0));
+        assert_eq!((reply.root_id, reply.depth), (root.id, 1));
+        assert_eq!((nested.root_id, nested.depth), (root.id, 2));
+    }
+
+    #[test]
+    fn threads_list_replies_depth_first_in_order() {
+        let root = comment(None);
+        let first = comment(Some(&root));
+        let second = comment(Some(&root));
+        let nested = comment(Some(&first));
+        let replies = vec![first.clone(), second.clone(), nested.clone()];
+
+        let mut items = vec![root.clone()];
+        push_replies(&mut items, &replies, root.id);
+
+        let ids: Vec<Option<ObjectId>> = items.iter().map(|item| item.id).collect();
+        assert_eq!(ids, [root.id, first.id, nested.id, second.id]);
+    }
+
+    #[test]
+    fn soft_deleted_comments_keep_their_replies_in_the_thread() {
+        let mut root = comment(None);
+        root.deleted = true;
+        root.body = String::new();
+        let reply = comment(Some(&root));
+        let replies = vec![reply.clone()];
+
+        let mut items = vec![root.clone()];
+        push_replies(&mut items, &replies, root.id);
+
+        assert_eq!(items, [root, reply]);
+    }
+
+    #[test]
+    fn soft_delete_blanks_the_body_and_flags_the_comment() {
+        let update = Comment::soft_delete(Utc::now()).unwrap();
+        let set = update.get_document("$set").unwrap();
+
+        assert_eq!(set.get_str("body").unwrap(), "");
+        assert!(set.get_bool("deleted").unwrap());
+        assert!(set.contains_key("updatedAt"));
+    }
+
+    #[test]
+    fn authors_of_the_comment_or_the_post_may_delete_it() {
+        let writer = user(Role::Reader);
+        let post_author = user(Role::Reader);
+        let post = post(post_author.auth_id);
+        let mut comment = comment(None);
+        comment.user_id = writer.id.unwrap();
+
+        assert!(may_delete(&writer, &comment, &post));
+        assert!(may_delete(&post_author, &comment, &post));
+    }
+
+    #[test]
+    fn only_moderators_may_delete_other_comments() {
+        let post = post(ObjectId::new());
+        let comment = comment(None);
+
+        assert!(!may_delete(&user(Role::Reader), &comment, &post));
+        assert!(!may_delete(&user(Role::Author), &comment, &post));
+        assert!(may_delete(&user(Role::Editor), &comment, &post));
+        assert!(may_delete(&user(Role::Admin), &comment, &post));
+    }
+}
@@ -0,0 +1,266 @@
+use crate::error::{AppError, AppResult};
+use crate::utils::token::constant_time_eq;
+use crate::AppState;
+
+use axum::body::Body;
+use axum::extract::State;
+use axum::headers::{Cookie, HeaderMapExt};
+use axum::http::header::{HeaderName, ORIGIN, SET_COOKIE};
+use axum::http::{HeaderMap, Method, Request};
+use axum::middleware::Next;
+use axum::response::{AppendHeaders, IntoResponse, Response};
+use nanoid::nanoid;
+
+/// Header clients echo the `csrf_token` cookie in, see `protect`.
+const CSRF_HEADER: &str = "x-csrf-token";
+const SEC_FETCH_SITE: &str = "sec-fetch-site";
+/// Default lifetime of both cookies, matching the longest a session can live.
+const DEFAULT_MAX_AGE: i64 = 30 * 24 * 60 * 60;
+
+/// The `Set-Cookie` headers for the session and CSRF cookies, always sent together.
+pub(crate) type SetCookies = AppendHeaders<[(HeaderName, String); 2]>;
+/// The `Set-Cookie` header of a cookie issued on its own.
+pub(crate) type SetCookie = AppendHeaders<[(HeaderName, String); 1]>;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum SameSite {
+    Strict,
+    Lax,
+    None,
+}
+
+/// Attributes every cookie of the API is issued with. Read from the environment
+/// once on startup, see `CookiePolicy::from_env`.
+#[derive(Clone, Debug)]
+pub(crate) struct CookiePolicy {
+    pub secure: bool,
+    pub same_site: SameSite,
+    pub path: String,
+    pub domain: Option<String>,
+    /// Seconds, `Max-Age` of freshly issued cookies.
+    pub max_age: i64,
+}
+
+impl CookiePolicy {
+    /// Reads `BLOG_COOKIE_SECURE`, `BLOG_COOKIE_SAME_SITE`, `BLOG_COOKIE_PATH`,
+    /// `BLOG_COOKIE_DOMAIN` and `BLOG_COOKIE_MAX_AGE`. Cookies are `Secure` by default
+    /// whenever the blog is served over HTTPS.
+    pub(crate) fn from_env(site_url: &str) -> Self {
+        let secure = match std::env::var("BLOG_COOKIE_SECURE") {
+            Ok(secure) => secure == "true" || secure == "1",
+            Err(_) => site_url.starts_with("https://"),
+        };
+        let same_site = match std::env::var("BLOG_COOKIE_SAME_SITE").as_deref() {
+            Ok("Strict") | Ok("strict") => SameSite::Strict,
+            Ok("None") | Ok("none") => SameSite::None,
+            _ => SameSite::Lax,
+        };
+        let max_age = std::env::var("BLOG_COOKIE_MAX_AGE")
+            .ok()
+            .and_then(|max_age| max_age.parse().ok())
+            .unwrap_or(DEFAULT_MAX_AGE);
+
+        CookiePolicy {
+            // browsers drop SameSite=None cookies that are not Secure
+            secure: secure || same_site == SameSite::None,
+            same_site,
+            path: std::env::var("BLOG_COOKIE_PATH").unwrap_or(String::from("/")),
+            domain: std::env::var("BLOG_COOKIE_DOMAIN").ok(),
+            max_age,
+        }
+    }
+
+    fn build(&self, name: &str, value: &str, max_age: i64, http_only: bool, same_site: SameSite) -> String {
+        let mut cookie = format!("{name}={value}; Path={}; Max-Age={max_age}", self.path);
+        if let Some(domain) = &self.domain {
+            cookie.push_str(&format!("; Domain={domain}"));
+        }
+        if http_only {
+            cookie.push_str("; HttpOnly");
+        }
+        if self.secure {
+            cookie.push_str("; Secure");
+        }
+        let same_site = match same_site {
+            SameSite::Strict => "Strict",
+            SameSite::Lax => "Lax",
+            SameSite::None => "None",
+        };
+        cookie.push_str(&format!("; SameSite={same_site}"));
+        cookie
+    }
+
+    /// Issues the session cookie along with a new CSRF token. The token cookie is
+    /// readable by scripts, which is what lets the client echo it in `X-CSRF-Token`.
+    pub(crate) fn sign_in(&self, session_id: &str) -> SetCookies {
+        AppendHeaders([
+            (SET_COOKIE, self.build("session_id", session_id, self.max_age, true, self.same_site)),
+            (SET_COOKIE, self.build("csrf_token", &nanoid!(32), self.max_age, false, self.same_site)),
+        ])
+    }
+
+    pub(crate) fn sign_out(&self) -> SetCookies {
+        AppendHeaders([
+            (SET_COOKIE, self.build("session_id", "", 0, true, self.same_site)),
+            (SET_COOKIE, self.build("csrf_token", "", 0, false, self.same_site)),
+        ])
+    }
+
+    /// Remembers a sign in in progress at the OIDC provider. `SameSite=Lax` at the
+    /// strictest, or browsers would leave it out of the provider's redirect back.
+    pub(crate) fn oidc_login(&self, login: &str, max_age: i64) -> SetCookie {
+        let same_site = match self.same_site {
+            SameSite::Strict => SameSite::Lax,
+            same_site => same_site,
+        };
+        AppendHeaders([(SET_COOKIE, self.build("oidc_login", login, max_age, true, same_site))])
+    }
+
+    pub(crate) fn clear_oidc_login(&self) -> SetCookie {
+        AppendHeaders([(SET_COOKIE, self.build("oidc_login", "", 0, true, SameSite::Lax))])
+    }
+}
+
+/// CSRF check for every request that can change state.
+///
+/// Requests carrying a session cookie use a double-submit token: they must repeat
+/// the `csrf_token` cookie in the `X-CSRF-Token` header, which a cross-site form or
+/// script cannot do. Requests without a session, such as signing in, have no token
+/// yet, so their `Origin` must be the origin of `BLOG_URL`, or without `Origin`, their
+/// `Sec-Fetch-Site` must be `same-origin` or `none`. Browsers send at least one of
+/// them on cross-site requests, which keeps a hostile page from signing a visitor
+/// into another account. Clients that send neither, which are not browsers, pass.
+pub(crate) async fn protect(
+    State(state): State<AppState>,
+    request: Request<Body>,
+    next: Next<Body>,
+) -> Response {
+    match check(request.method(), request.headers(), &state.site_url) {
+        Ok(()) => next.run(request).await,
+        Err(error) => error.into_response(),
+    }
+}
+
+fn check(method: &Method, headers: &HeaderMap, site_url: &str) -> AppResult<()> {
+    if matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS) {
+        return Ok(());
+    }
+
+    let cookie = headers.typed_get::<Cookie>();
+    if cookie.as_ref().and_then(|cookie| cookie.get("session_id")).is_none() {
+        let header = |name: &str| headers.get(name).and_then(|value| value.to_str().ok());
+        let same_origin = match (header(ORIGIN.as_str()), header(SEC_FETCH_SITE)) {
+            (Some(origin), _) => origin == site_origin(site_url),
+            (None, Some(site)) => site == "same-origin" || site == "none",
+            (None, None) => true,
+        };
+        return if same_origin { Ok(()) } else { Err(AppError::CsrfMismatch) };
+    }
+
+    let expected = cookie.as_ref().and_then(|cookie| cookie.get("csrf_token"));
+    let submitted = headers.get(CSRF_HEADER).and_then(|value| value.to_str().ok());
+    match (expected, submitted) {
+        (Some(expected), Some(submitted)) if constant_time_eq(expected, submitted) => Ok(()),
+        _ => Err(AppError::CsrfMismatch),
+    }
+}
+
+/// `https://example.com` for `https://example.com/blog`, what browsers send as `Origin`.
+fn site_origin(site_url: &str) -> &str {
+    let host_start = site_url.find("://").map_or(0, |scheme| scheme + 3);
+    match site_url[host_start..].find('/') {
+        Some(path) => &site_url[..host_start + path],
+        None => site_url,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::HeaderValue;
+
+    const SITE_URL: &str = "https://example.com/blog";
+
+    fn policy(same_site: SameSite) -> CookiePolicy {
+        CookiePolicy {
+            secure: true,
+            same_site,
+            path: String::from("/"),
+            domain: None,
+            max_age: 60,
+        }
+    }
+
+    fn headers(pairs: &[(&'static str, &'static str)]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for (name, value) in pairs {
+            headers.insert(*name, HeaderValue::from_static(value));
+        }
+        headers
+    }
+
+    #[test]
+    fn build_sets_every_attribute() {
+        let mut policy = policy(SameSite::Strict);
+        policy.domain = Some(String::from("example.com"));
+
+        assert_eq!(
+            policy.build("session_id", "abc", 60, true, policy.same_site),
+            "session_id=abc; Path=/; Max-Age=60; Domain=example.com; HttpOnly; Secure; SameSite=Strict",
+        );
+    }
+
+    #[test]
+    fn build_leaves_out_unset_flags() {
+        let mut policy = policy(SameSite::Lax);
+        policy.secure = false;
+
+        assert_eq!(
+            policy.build("csrf_token", "abc", 0, false, policy.same_site),
+            "csrf_token=abc; Path=/; Max-Age=0; SameSite=Lax",
+        );
+    }
+
+    #[test]
+    fn oidc_login_is_never_strict() {
+        let AppendHeaders([(_, cookie)]) = policy(SameSite::Strict).oidc_login("login", 60);
+        assert!(cookie.ends_with("SameSite=Lax"));
+    }
+
+    #[test]
+    fn safe_methods_pass() {
+        let headers = headers(&[("origin", "https://evil.example")]);
+        assert!(check(&Method::GET, &headers, SITE_URL).is_ok());
+    }
+
+    #[test]
+    fn session_requests_need_the_matching_token() {
+        let valid = headers(&[("cookie", "session_id=s; csrf_token=t"), ("x-csrf-token", "t")]);
+        let wrong = headers(&[("cookie", "session_id=s; csrf_token=t"), ("x-csrf-token", "u")]);
+        let missing = headers(&[("cookie", "session_id=s; csrf_token=t")]);
+
+        assert!(check(&Method::POST, &valid, SITE_URL).is_ok());
+        assert!(matches!(check(&Method::POST, &wrong, SITE_URL), Err(AppError::CsrfMismatch)));
+        assert!(matches!(check(&Method::POST, &missing, SITE_URL), Err(AppError::CsrfMismatch)));
+    }
+
+    #[test]
+    fn sessionless_requests_must_come_from_the_site() {
+        let same = headers(&[("origin", "https://example.com")]);
+        let cross = headers(&[("origin", "https://evil.example")]);
+        let cross_fetch = headers(&[("sec-fetch-site", "cross-site")]);
+        let direct = headers(&[("sec-fetch-site", "none")]);
+
+        assert!(check(&Method::POST, &same, SITE_URL).is_ok());
+        assert!(check(&Method::POST, &direct, SITE_URL).is_ok());
+        assert!(check(&Method::POST, &HeaderMap::new(), SITE_URL).is_ok());
+        assert!(matches!(check(&Method::POST, &cross, SITE_URL), Err(AppError::CsrfMismatch)));
+        assert!(matches!(check(&Method::POST, &cross_fetch, SITE_URL), Err(AppError::CsrfMismatch)));
+    }
+
+    #[test]
+    fn site_origin_drops_the_path() {
+        assert_eq!(site_origin("https://example.com/blog"), "https://example.com");
+        assert_eq!(site_origin("http://localhost:4000"), "http://localhost:4000");
+    }
+}
@@ -0,0 +1,293 @@
+use axum::http::header::{CONTENT_TYPE, RETRY_AFTER};
+use axum::http::{HeaderValue, StatusCode};
+use axum::response::{IntoResponse, Response};
+use mongodb::bson;
+use mongodb::error::{ErrorKind, WriteFailure};
+use serde::Serialize;
+
+/// MongoDB server error code for unique index violations.
+const DUPLICATE_KEY: i32 = 11000;
+
+pub(crate) type AppResult<T> = Result<T, AppError>;
+
+#[derive(Serialize, Debug, PartialEq)]
+pub(crate) struct FieldError {
+    pub field: &'static str,
+    pub message: String,
+}
+
+impl FieldError {
+    pub(crate) fn new(field: &'static str, message: impl Into<String>) -> Self {
+        FieldError {
+            field,
+            message: message.into(),
+        }
+    }
+}
+
+/// Every way a request can fail. Responds with an RFC 7807 `application/problem+json`
+/// body whose `code` stays the same across releases, so clients can match on it.
+#[derive(Debug)]
+pub(crate) enum AppError {
+    BadRequest(String),
+    /// No valid session, or the credentials did not match.
+    Unauthorized,
+    /// Signed in, but not allowed to do this.
+    Forbidden,
+    /// A cookie authenticated request without the matching `X-CSRF-Token` header, or
+    /// a request without a session sent from another site.
+    CsrfMismatch,
+    /// The account has to verify its email before doing this.
+    EmailNotVerified,
+    NotFound,
+    Conflict(String),
+    /// Too many failed attempts, the client should wait `retry_after` seconds.
+    TooManyRequests { retry_after: u64 },
+    Validation(Vec<FieldError>),
+    Database(mongodb::error::Error),
+    Internal(String),
+}
+
+#[derive(Serialize)]
+struct Problem<'a> {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: &'a str,
+    status: u16,
+    code: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    errors: Option<&'a [FieldError]>,
+}
+
+impl AppError {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            AppError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            AppError::Unauthorized => StatusCode::UNAUTHORIZED,
+            AppError::Forbidden | AppError::CsrfMismatch | AppError::EmailNotVerified => {
+                StatusCode::FORBIDDEN
+            }
+            AppError::NotFound => StatusCode::NOT_FOUND,
+            AppError::Conflict(_) => StatusCode::CONFLICT,
+            AppError::TooManyRequests { .. } => StatusCode::TOO_MANY_REQUESTS,
+            AppError::Validation(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            AppError::Database(_) | AppError::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    pub(crate) fn code(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "bad_request",
+            AppError::Unauthorized => "unauthorized",
+            AppError::Forbidden => "forbidden",
+            AppError::CsrfMismatch => "csrf_failed",
+            AppError::EmailNotVerified => "email_not_verified",
+            AppError::NotFound => "not_found",
+            AppError::Conflict(_) => "conflict",
+            AppError::TooManyRequests { .. } => "too_many_requests",
+            AppError::Validation(_) => "validation_failed",
+            AppError::Database(_) => "database_error",
+            AppError::Internal(_) => "internal_error",
+        }
+    }
+
+    fn title(&self) -> &'static str {
+        match self {
+            AppError::BadRequest(_) => "The request is malformed.",
+            AppError::Unauthorized => "Authentication is required.",
+            AppError::Forbidden => "You are not allowed to do this.",
+            AppError::CsrfMismatch => "The request failed the CSRF check.",
+            AppError::EmailNotVerified => "Verify your email address first.",
+            AppError::NotFound => "The resource does not exist.",
+            AppError::Conflict(_) => "The resource conflicts with an existing one.",
+            AppError::TooManyRequests { .. } => "Too many attempts, try again later.",
+            AppError::Validation(_) => "Some fields are invalid.",
+            AppError::Database(_) => "The database could not handle the request.",
+            AppError::Internal(_) => "Something went wrong on our side.",
+        }
+    }
+
+    fn detail(&self) -> Option<String> {
+        match self {
+            AppError::BadRequest(detail) | AppError::Conflict(detail) => Some(detail.clone()),
+            AppError::TooManyRequests { retry_after } => Some(format!("Retry in {retry_after} seconds.")),
+            // internal details stay in the server logs
+            _ => None,
+        }
+    }
+}
+
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        match &self {
+            AppError::Database(error) => eprintln!("Database error: {error}"),
+            AppError::Internal(error) => eprintln!("Internal error: {error}"),
+            _ => (),
+        }
+
+        let errors = match &self {
+            AppError::Validation(errors) => Some(errors.as_slice()),
+            _ => None,
+        };
+        let problem = Problem {
+            problem_type: format!("/problems/{}", self.code()),
+            title: self.title(),
+            status: status.as_u16(),
+            code: self.code(),
+            detail: self.detail(),
+            errors,
+        };
+        let body = serde_json::to_string(&problem).unwrap_or_default();
+
+        let mut response = (status, [(CONTENT_TYPE, "application/problem+json")], body).into_response();
+        if let AppError::TooManyRequests { retry_after } = self {
+            response.headers_mut().insert(RETRY_AFTER, HeaderValue::from(retry_after));
+        }
+        response
+    }
+}
+
+impl From<mongodb::error::Error> for AppError {
+    fn from(error: mongodb::error::Error) -> Self {
+        if let ErrorKind::Write(WriteFailure::WriteError(write_error)) = error.kind.as_ref() {
+            if write_error.code == DUPLICATE_KEY {
+                return AppError::Conflict(String::from("A unique field is already taken."));
+            }
+        }
+        AppError::Database(error)
+    }
+}
+
+impl From<bson::ser::Error> for AppError {
+    fn from(error: bson::ser::Error) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<bson::de::Error> for AppError {
+    fn from(error: bson::de::Error) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<bcrypt::BcryptError> for AppError {
+    fn from(error: bcrypt::BcryptError) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+impl From<argon2::password_hash::Error> for AppError {
+    fn from(error: argon2::password_hash::Error) -> Self {
+        AppError::Internal(error.to_string())
+    }
+}
+
+/// Documents loaded from MongoDB always carry an `_id`; this turns the impossible
+/// `None` into an error rather than a panic.
+pub(crate) fn stored_id(id: Option<bson::oid::ObjectId>) -> AppResult<bson::oid::ObjectId> {
+    id.ok_or_else(|| AppError::Internal(String::from("Stored document has no _id")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::body::HttpBody;
+    use mongodb::error::WriteError;
+
+    async fn problem(error: AppError) -> (StatusCode, serde_json::Value) {
+        let mut response = error.into_response();
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/problem+json");
+        let body = response.body_mut().data().await.unwrap().unwrap();
+
+        (response.status(), serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn every_variant_has_its_status_and_code() {
+        let database = mongodb::error::Error::custom("down");
+        let cases = [
+            (AppError::BadRequest(String::from("bad")), StatusCode::BAD_REQUEST, "bad_request"),
+            (AppError::Unauthorized, StatusCode::UNAUTHORIZED, "unauthorized"),
+            (AppError::Forbidden, StatusCode::FORBIDDEN, "forbidden"),
+            (AppError::CsrfMismatch, StatusCode::FORBIDDEN, "csrf_failed"),
+            (AppError::EmailNotVerified, StatusCode::FORBIDDEN, "email_not_verified"),
+            (AppError::NotFound, StatusCode::NOT_FOUND, "not_found"),
+            (AppError::Conflict(String::from("taken")), StatusCode::CONFLICT, "conflict"),
+            (AppError::TooManyRequests { retry_after: 30 }, StatusCode::TOO_MANY_REQUESTS, "too_many_requests"),
+            (AppError::Validation(vec![]), StatusCode::UNPROCESSABLE_ENTITY, "validation_failed"),
+            (AppError::Database(database), StatusCode::INTERNAL_SERVER_ERROR, "database_error"),
+            (AppError::Internal(String::from("oops")), StatusCode::INTERNAL_SERVER_ERROR, "internal_error"),
+        ];
+
+        for (error, status, code) in cases {
+            let title = error.title();
+            let (response_status, body) = problem(error).await;
+
+            assert_eq!(response_status, status, "{code}");
+            assert_eq!(body["status"], status.as_u16(), "{code}");
+            assert_eq!(body["code"], code);
+            assert_eq!(body["type"], format!("/problems/{code}"));
+            assert_eq!(body["title"], title);
+        }
+    }
+
+    #[tokio::test]
+    async fn details_are_shown_for_client_errors_only() {
+        let (_, body) = problem(AppError::Conflict(String::from("Tag rust already exists."))).await;
+        assert_eq!(body["detail"], "Tag rust already exists.");
+
+        let (_, body) = problem(AppError::Internal(String::from("secret stack trace"))).await;
+        assert!(body.get("detail").is_none());
+    }
+
+    #[tokio::test]
+    async fn validation_errors_list_the_fields() {
+        let error = AppError::Validation(vec![FieldError::new("email", "must be an email address")]);
+        let (_, body) = problem(error).await;
+
+        assert_eq!(
+            body["errors"],
+            serde_json::json!([{ "field": "email", "message": "must be an email address" }])
+        );
+    }
+
+    #[tokio::test]
+    async fn too_many_requests_sets_retry_after() {
+        let response = AppError::TooManyRequests { retry_after: 42 }.into_response();
+
+        assert_eq!(response.headers()[RETRY_AFTER], "42");
+        let (_, body) = problem(AppError::TooManyRequests { retry_after: 42 }).await;
+        assert_eq!(body["detail"], "Retry in 42 seconds.");
+    }
+
+    #[test]
+    fn other_errors_have_no_retry_after() {
+        let response = AppError::Unauthorized.into_response();
+
+        assert!(response.headers().get(RETRY_AFTER).is_none());
+    }
+
+    fn write_error(code: i32) -> mongodb::error::Error {
+        let write_error: WriteError = bson::from_document(bson::doc! { "code": code, "errmsg": "failed" }).unwrap();
+        mongodb::error::Error::from(ErrorKind::Write(WriteFailure::WriteError(write_error)))
+    }
+
+    #[test]
+    fn duplicate_keys_are_conflicts() {
+        let error = AppError::from(write_error(DUPLICATE_KEY));
+
+        assert_eq!(error.status(), StatusCode::CONFLICT);
+        assert!(matches!(error, AppError::Conflict(_)));
+    }
+
+    #[test]
+    fn other_write_errors_are_database_errors() {
+        let error = AppError::from(write_error(121));
+
+        assert_eq!(error.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(matches!(error, AppError::Database(_)));
+    }
+}
@@ -0,0 +1,449 @@
+use crate::error::{AppError, AppResult};
+use crate::post::{escape, Post};
+use crate::utils::slug::slugify;
+use crate::AppState;
+
+use axum::extract::{Query, State};
+use axum::http::header::{CONTENT_TYPE, ETAG, IF_NONE_MATCH};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Response};
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+/// Number of most recent posts included in every feed.
+const FEED_LENGTH: i64 = 20;
+
+#[derive(Deserialize, Debug, Default)]
+pub(crate) struct FeedQuery {
+    pub tag: Option<String>,
+    pub author_id: Option<ObjectId>,
+}
+
+struct FeedEntry {
+    post: Post,
+    author: Option<String>,
+    url: String,
+}
+
+struct FeedData {
+    title: String,
+    home_page_url: String,
+    updated: DateTime<Utc>,
+    entries: Vec<FeedEntry>,
+}
+
+#[derive(Serialize)]
+struct JsonFeed {
+    version: &'static str,
+    title: String,
+    home_page_url: String,
+    feed_url: String,
+    items: Vec<JsonFeedItem>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedItem {
+    id: String,
+    url: String,
+    title: String,
+    content_html: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_published: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    date_modified: Option<String>,
+    authors: Vec<JsonFeedAuthor>,
+    tags: Vec<String>,
+}
+
+#[derive(Serialize)]
+struct JsonFeedAuthor {
+    name: String,
+}
+
+/// Loads the most recent published posts matching `query`, along with their
+/// authors' display names.
+async fn load(state: &AppState, query: &FeedQuery) -> mongodb::error::Result<FeedData> {
+    let mut filter = bson::doc! {
+        "$or": [{ "status": "Published" }, { "status": { "$exists": false } }]
+    };
+    let mut title = state.site_title.clone();
+    if let Some(tag) = &query.tag {
+        let tag = slugify(tag);
+        title = format!("{title} - #{tag}");
+        filter.insert("tags", tag);
+    }
+    if let Some(author_id) = query.author_id {
+        filter.insert("author_id", author_id);
+    }
+
+    let options = FindOptions::builder()
+        .sort(bson::doc! { "createdAt": -1, "_id": -1 })
+        .limit(FEED_LENGTH)
+        .build();
+    let mut cursor = state.posts_collection.find(filter, options).await?;
+    let mut posts: Vec<Post> = vec![];
+    while let Some(post) = cursor.next().await {
+        let mut post = post?;
+        post.ensure_rendered();
+        posts.push(post);
+    }
+
+    let author_ids: Vec<ObjectId> = posts.iter().map(|post| post.author_id).collect();
+    let mut users_cursor = state
+        .users_collection
+        .find(bson::doc! { "auth_id": { "$in": author_ids } }, None)
+        .await?;
+    let mut authors: HashMap<ObjectId, String> = HashMap::new();
+    while let Some(user) = users_cursor.next().await {
+        let user = user?;
+        authors.insert(user.auth_id, user.display_name);
+    }
+
+    if let Some(author_id) = query.author_id {
+        if let Some(author) = authors.get(&author_id) {
+            title = format!("{title} - {author}");
+        }
+    }
+
+    let updated = posts
+        .iter()
+        .filter_map(|post| post.updated_at.or(post.created_at))
+        .max()
+        // an empty feed reports the Unix epoch so its ETag stays the same between requests
+        .unwrap_or_default();
+    let entries = posts
+        .into_iter()
+        .map(|post| FeedEntry {
+            author: authors.get(&post.author_id).cloned(),
+            url: format!("{}/api/posts/by-slug/{}", state.site_url, post.slug),
+            post,
+        })
+        .collect();
+
+    Ok(FeedData {
+        title,
+        home_page_url: state.site_url.clone(),
+        updated,
+        entries,
+    })
+}
+
+/// Responds with `body`, or with `304 Not Modified` when the client already holds it.
+fn respond(headers: &HeaderMap, content_type: &'static str, body: String) -> Response {
+    let etag = format!("\"{:x}\"", Sha256::digest(body.as_bytes()));
+    let cached = headers
+        .get(IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.split(',').any(|tag| tag.trim() == etag || tag.trim() == "*"))
+        .unwrap_or(false);
+
+    if cached {
+        return (StatusCode::NOT_MODIFIED, [(ETAG, etag)]).into_response();
+    }
+
+    (
+        StatusCode::OK,
+        [(CONTENT_TYPE, String::from(content_type)), (ETAG, etag)],
+        body,
+    )
+        .into_response()
+}
+
+/// Builds the feed URL for `path`, keeping the tag and author filters.
+fn feed_url(site_url: &str, path: &str, query: &FeedQuery) -> String {
+    let mut pairs: Vec<(&str, String)> = vec![];
+    if let Some(tag) = &query.tag {
+        pairs.push(("tag", tag.clone()));
+    }
+    if let Some(author_id) = query.author_id {
+        pairs.push(("author_id", author_id.to_hex()));
+    }
+    match serde_urlencoded::to_string(pairs) {
+        Ok(query) if !query.is_empty() => format!("{site_url}{path}?{query}"),
+        _ => format!("{site_url}{path}"),
+    }
+}
+
+fn entry_id(entry: &FeedEntry) -> String {
+    entry
+        .post
+        .id
+        .map(|id| id.to_hex())
+        .unwrap_or_else(|| entry.post.slug.clone())
+}
+
+fn rss_xml(feed: &FeedData, self_url: &str) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(r#"<rss version="2.0" xmlns:atom="http://www.w3.org/2005/Atom" xmlns:dc="http://purl.org/dc/elements/1.1/"><channel>"#);
+    xml.push_str(&format!("<title>{}</title>", escape(&feed.title)));
+    xml.push_str(&format!("<link>{}</link>", escape(&feed.home_page_url)));
+    xml.push_str(&format!("<description>{}</description>", escape(&feed.title)));
+    xml.push_str(&format!(
+        r#"<atom:link href="{}" rel="self" type="application/rss+xml"/>"#,
+        escape(self_url)
+    ));
+    xml.push_str(&format!("<lastBuildDate>{}</lastBuildDate>", feed.updated.to_rfc2822()));
+    for entry in &feed.entries {
+        xml.push_str("<item>");
+        xml.push_str(&format!("<title>{}</title>", escape(&entry.post.title)));
+        xml.push_str(&format!("<link>{}</link>", escape(&entry.url)));
+        xml.push_str(&format!(r#"<guid isPermaLink="false">{}</guid>"#, entry_id(entry)));
+        if let Some(created_at) = entry.post.created_at {
+            xml.push_str(&format!("<pubDate>{}</pubDate>", created_at.to_rfc2822()));
+        }
+        if let Some(author) = &entry.author {
+            xml.push_str(&format!("<dc:creator>{}</dc:creator>", escape(author)));
+        }
+        for tag in &entry.post.tags {
+            xml.push_str(&format!("<category>{}</category>", escape(tag)));
+        }
+        xml.push_str(&format!(
+            "<description>{}</description>",
+            escape(&entry.post.content_html)
+        ));
+        xml.push_str("</item>");
+    }
+    xml.push_str("</channel></rss>");
+    xml
+}
+
+fn atom_xml(feed: &FeedData, self_url: &str) -> String {
+    let mut xml = String::from(r#"<?xml version="1.0" encoding="UTF-8"?>"#);
+    xml.push_str(r#"<feed xmlns="http://www.w3.org/2005/Atom">"#);
+    xml.push_str(&format!("<title>{}</title>", escape(&feed.title)));
+    xml.push_str(&format!("<id>{}</id>", escape(self_url)));
+    xml.push_str(&format!(r#"<link href="{}" rel="self"/>"#, escape(self_url)));
+    xml.push_str(&format!(r#"<link href="{}"/>"#, escape(&feed.home_page_url)));
+    xml.push_str(&format!("<updated>{}</updated>", feed.updated.to_rfc3339()));
+    for entry in &feed.entries {
+        xml.push_str("<entry>");
+        xml.push_str(&format!("<title>{}</title>", escape(&entry.post.title)));
+        xml.push_str(&format!("<id>{}</id>", escape(&entry.url)));
+        xml.push_str(&format!(r#"<link href="{}"/>"#, escape(&entry.url)));
+        let published = entry.post.created_at.unwrap_or(feed.updated);
+        xml.push_str(&format!("<published>{}</published>", published.to_rfc3339()));
+        xml.push_str(&format!(
+            "<updated>{}</updated>",
+            entry.post.updated_at.unwrap_or(published).to_rfc3339()
+        ));
+        let author = entry.author.as_deref().unwrap_or(&feed.title);
+        xml.push_str(&format!("<author><name>{}</name></author>", escape(author)));
+        for tag in &entry.post.tags {
+            xml.push_str(&format!(r#"<category term="{}"/>"#, escape(tag)));
+        }
+        xml.push_str(&format!(
+            r#"<content type="html">{}</content>"#,
+            escape(&entry.post.content_html)
+        ));
+        xml.push_str("</entry>");
+    }
+    xml.push_str("</feed>");
+    xml
+}
+
+pub(crate) async fn rss(
+    headers: HeaderMap,
+    Query(query): Query<FeedQuery>,
+    State(state): State<AppState>,
+) -> AppResult<Response> {
+    let feed = load(&state, &query).await?;
+    let xml = rss_xml(&feed, &feed_url(&state.site_url, "/feed.xml", &query));
+
+    Ok(respond(&headers, "application/rss+xml; charset=utf-8", xml))
+}
+
+pub(crate) async fn atom(
+    headers: HeaderMap,
+    Query(query): Query<FeedQuery>,
+    State(state): State<AppState>,
+) -> AppResult<Response> {
+    let feed = load(&state, &query).await?;
+    let xml = atom_xml(&feed, &feed_url(&state.site_url, "/atom.xml", &query));
+
+    Ok(respond(&headers, "application/atom+xml; charset=utf-8", xml))
+}
+
+pub(crate) async fn json(
+    headers: HeaderMap,
+    Query(query): Query<FeedQuery>,
+    State(state): State<AppState>,
+) -> AppResult<Response> {
+    let feed = load(&state, &query).await?;
+
+    let feed_url = feed_url(&state.site_url, "/feed.json", &query);
+    let items = feed
+        .entries
+        .into_iter()
+        .map(|entry| JsonFeedItem {
+            id: entry_id(&entry),
+            url: entry.url,
+            title: entry.post.title,
+            content_html: entry.post.content_html,
+            date_published: entry.post.created_at.map(|date| date.to_rfc3339()),
+            date_modified: entry.post.updated_at.map(|date| date.to_rfc3339()),
+            authors: entry
+                .author
+                .map(|name| vec![JsonFeedAuthor { name }])
+                .unwrap_or_default(),
+            tags: entry.post.tags,
+        })
+        .collect();
+    let json_feed = JsonFeed {
+        version: "https://jsonfeed.org/version/1.1",
+        title: feed.title,
+        home_page_url: feed.home_page_url,
+        feed_url,
+        items,
+    };
+    let body = serde_json::to_string(&json_feed).map_err(|error| AppError::Internal(error.to_string()))?;
+
+    Ok(respond(&headers, "application/feed+json; charset=utf-8", body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::{ContentFormat, PostStatus};
+    use axum::http::HeaderValue;
+
+    const BODY: &str = "<rss></rss>";
+
+    fn etag() -> String {
+        format!("\"{:x}\"", Sha256::digest(BODY.as_bytes()))
+    }
+
+    fn if_none_match(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(IF_NONE_MATCH, HeaderValue::from_str(value).unwrap());
+        headers
+    }
+
+    fn feed() -> FeedData {
+        let post = Post {
+            id: Some(ObjectId::new()),
+            author_id: ObjectId::new(),
+            title: String::from("Fish & <Chips>"),
+            slug: String::from("fish-chips"),
+            previous_slugs: vec![],
+            content: String::from("<p>\"Hot\" & salty</p>"),
+            content_format: ContentFormat::Html,
+            content_html: String::from("<p>\"Hot\" & salty</p>"),
+            tags: vec![String::from("food")],
+            category: None,
+            status: PostStatus::Published,
+            published_at: None,
+            created_at: None,
+            updated_at: None,
+        };
+        FeedData {
+            title: String::from("Tom's <blog>"),
+            home_page_url: String::from("https://example.com"),
+            updated: DateTime::default(),
+            entries: vec![FeedEntry {
+                post,
+                author: Some(String::from("Ann & Bob")),
+                url: String::from("https://example.com/api/posts/by-slug/fish-chips"),
+            }],
+        }
+    }
+
+    #[test]
+    fn respond_sends_the_body_with_an_etag() {
+        let response = respond(&HeaderMap::new(), "application/rss+xml", String::from(BODY));
+
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.headers()[ETAG], etag().as_str());
+        assert_eq!(response.headers()[CONTENT_TYPE], "application/rss+xml");
+    }
+
+    #[test]
+    fn respond_is_not_modified_for_a_matching_etag() {
+        let response = respond(&if_none_match(&etag()), "application/rss+xml", String::from(BODY));
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+        assert_eq!(response.headers()[ETAG], etag().as_str());
+    }
+
+    #[test]
+    fn respond_is_not_modified_when_the_etag_is_in_a_list() {
+        let headers = if_none_match(&format!("\"other\", {}", etag()));
+        let response = respond(&headers, "application/rss+xml", String::from(BODY));
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn respond_is_not_modified_for_a_wildcard() {
+        let response = respond(&if_none_match("*"), "application/rss+xml", String::from(BODY));
+
+        assert_eq!(response.status(), StatusCode::NOT_MODIFIED);
+    }
+
+    #[test]
+    fn respond_sends_the_body_for_a_stale_etag() {
+        let response = respond(&if_none_match("\"stale\""), "application/rss+xml", String::from(BODY));
+
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[test]
+    fn feed_url_without_filters_has_no_query() {
+        let url = feed_url("https://example.com", "/feed.xml", &FeedQuery::default());
+
+        assert_eq!(url, "https://example.com/feed.xml");
+    }
+
+    #[test]
+    fn feed_url_encodes_the_filters() {
+        let author_id = ObjectId::new();
+        let query = FeedQuery {
+            tag: Some(String::from("c++ & rust")),
+            author_id: Some(author_id),
+        };
+        let url = feed_url("https://example.com", "/atom.xml", &query);
+
+        assert_eq!(
+            url,
+            format!("https://example.com/atom.xml?tag=c%2B%2B+%26+rust&author_id={}", author_id.to_hex())
+        );
+    }
+
+    #[test]
+    fn rss_escapes_titles_and_content() {
+        let xml = rss_xml(&feed(), "https://example.com/feed.xml?tag=a&author_id=b");
+
+        assert!(xml.contains("<title>Tom&#39;s &lt;blog&gt;</title>"));
+        assert!(xml.contains("<title>Fish &amp; &lt;Chips&gt;</title>"));
+        assert!(xml.contains("<dc:creator>Ann &amp; Bob</dc:creator>"));
+        assert!(xml.contains("<description>&lt;p&gt;&quot;Hot&quot; &amp; salty&lt;/p&gt;</description>"));
+        assert!(xml.contains(r#"href="https://example.com/feed.xml?tag=a&amp;author_id=b""#));
+    }
+
+    #[test]
+    fn atom_escapes_titles_and_content() {
+        let xml = atom_xml(&feed(), "https://example.com/atom.xml?tag=a&author_id=b");
+
+        assert!(xml.contains("<title>Tom&#39;s &lt;blog&gt;</title>"));
+        assert!(xml.contains("<title>Fish &amp; &lt;Chips&gt;</title>"));
+        assert!(xml.contains("<author><name>Ann &amp; Bob</name></author>"));
+        assert!(xml.contains(r#"<content type="html">&lt;p&gt;&quot;Hot&quot; &amp; salty&lt;/p&gt;</content>"#));
+        assert!(xml.contains("<id>https://example.com/atom.xml?tag=a&amp;author_id=b</id>"));
+    }
+
+    #[test]
+    fn an_empty_feed_is_dated_at_the_unix_epoch() {
+        let feed = FeedData { entries: vec![], ..feed() };
+
+        let xml = atom_xml(&feed, "https://example.com/atom.xml");
+
+        assert!(xml.contains("<updated>1970-01-01T00:00:00+00:00</updated>"));
+    }
+}
@@ -1,15 +1,34 @@
+mod api_token;
 mod auth;
+mod comment;
+mod cookie;
+mod error;
+mod feed;
+mod mail;
+mod password;
 mod post;
 mod session;
+mod tag;
 mod user;
 mod utils;
+mod view;
 
-use crate::auth::Auth;
-use crate::post::Post;
+use crate::api_token::ApiToken;
+use crate::auth::{
+    Auth, LockoutEvent, LockoutPolicy, MagicLink, MagicLinkConfig, OidcConfig, PasswordPolicy,
+    PasswordReset, RequestLimit, Restrictions, SignInThrottle,
+};
+use crate::comment::Comment;
+use crate::cookie::CookiePolicy;
+use crate::mail::{MailLinks, Mailer};
+use crate::password::PasswordHasher;
+use crate::post::{Post, PostRevision};
+use crate::tag::Tag;
 use crate::user::User;
 use crate::utils::database::Crud;
 use axum::{
-    routing::{delete, get, patch, post},
+    middleware,
+    routing::{delete, get, patch, post, put},
     Router,
 };
 use mongodb::{
@@ -18,6 +37,9 @@ use mongodb::{
     Client, Collection,
 };
 use session::Session;
+use rand::Rng;
+use std::net::SocketAddr;
+use std::sync::Arc;
 
 #[derive(Clone)]
 struct AppState {
@@ -27,6 +49,34 @@ struct AppState {
     pub users_collection: Collection<User>,
     pub auths_collection: Collection<Auth>,
     pub sessions_collection: Collection<Session>,
+    pub tags_collection: Collection<Tag>,
+    pub post_revisions_collection: Collection<PostRevision>,
+    pub comments_collection: Collection<Comment>,
+    pub password_resets_collection: Collection<PasswordReset>,
+    pub magic_links_collection: Collection<MagicLink>,
+    pub api_tokens_collection: Collection<ApiToken>,
+    pub sign_in_throttles_collection: Collection<SignInThrottle>,
+    pub request_limits_collection: Collection<RequestLimit>,
+    pub lockout_events_collection: Collection<LockoutEvent>,
+    /// Public base URL of the blog, used for absolute links in feeds.
+    pub site_url: String,
+    pub site_title: String,
+    pub cookies: CookiePolicy,
+    /// Key signing the tokens the server hands out, like verification links.
+    pub secret: Arc<[u8]>,
+    pub mailer: Arc<dyn Mailer>,
+    /// Frontend pages the mailed verification and reset links open.
+    pub links: MailLinks,
+    pub passwords: PasswordHasher,
+    pub password_policy: PasswordPolicy,
+    /// What accounts may not do before verifying their email.
+    pub restrictions: Restrictions,
+    /// When failed sign ins lock an email or IP out.
+    pub lockout: LockoutPolicy,
+    /// The OpenID Connect provider to sign in with, if any.
+    pub oidc: Option<OidcConfig>,
+    /// How long mailed sign in links last, if they are enabled.
+    pub magic_link: Option<MagicLinkConfig>,
 }
 
 #[tokio::main]
@@ -35,42 +85,212 @@ async fn main() -> mongodb::error::Result<()> {
         .await
         .expect("Failed to connect to database.");
 
-    let app = Router::new().nest("/api", make_api()).with_state(AppState {
+    let posts_collection = client.database("blog").collection::<Post>("posts");
+    Post::create_indexes(&posts_collection)
+        .await
+        .expect("Failed to create posts indexes.");
+
+    let tags_collection = client.database("blog").collection::<Tag>("tags");
+    Tag::create_indexes(&tags_collection)
+        .await
+        .expect("Failed to create tags indexes.");
+    // counts used to include drafts, bring existing tags in line with published posts
+    if let Err(error) = Tag::recount_all(&posts_collection, &tags_collection).await {
+        eprintln!("Failed to recount tags: {error}");
+    }
+    tokio::spawn(Post::run_scheduler(posts_collection.clone(), tags_collection.clone()));
+
+    let post_revisions_collection = client
+        .database("blog")
+        .collection::<PostRevision>("post_revisions");
+    PostRevision::create_indexes(&post_revisions_collection)
+        .await
+        .expect("Failed to create post revisions indexes.");
+
+    let comments_collection = client.database("blog").collection::<Comment>("comments");
+    Comment::create_indexes(&comments_collection)
+        .await
+        .expect("Failed to create comments indexes.");
+
+    let auths_collection = client.database("blog").collection::<Auth>("auths");
+    // also migrates old accounts, which should not keep the blog from starting
+    if let Err(error) = Auth::create_indexes(&auths_collection).await {
+        eprintln!("Failed to create auths indexes: {error}");
+    }
+
+    let sessions_collection = client.database("blog").collection::<Session>("sessions");
+    Session::create_indexes(&sessions_collection)
+        .await
+        .expect("Failed to create sessions indexes.");
+
+    let password_resets_collection = client
+        .database("blog")
+        .collection::<PasswordReset>("password_resets");
+    PasswordReset::create_indexes(&password_resets_collection)
+        .await
+        .expect("Failed to create password resets indexes.");
+
+    let magic_links_collection = client
+        .database("blog")
+        .collection::<MagicLink>("magic_links");
+    MagicLink::create_indexes(&magic_links_collection)
+        .await
+        .expect("Failed to create magic links indexes.");
+
+    let api_tokens_collection = client.database("blog").collection::<ApiToken>("api_tokens");
+    ApiToken::create_indexes(&api_tokens_collection)
+        .await
+        .expect("Failed to create API tokens indexes.");
+
+    let sign_in_throttles_collection = client
+        .database("blog")
+        .collection::<SignInThrottle>("sign_in_throttles");
+    SignInThrottle::create_indexes(&sign_in_throttles_collection)
+        .await
+        .expect("Failed to create sign in throttles indexes.");
+
+    let request_limits_collection = client
+        .database("blog")
+        .collection::<RequestLimit>("request_limits");
+    RequestLimit::create_indexes(&request_limits_collection)
+        .await
+        .expect("Failed to create request limits indexes.");
+
+    let site_url = std::env::var("BLOG_URL").unwrap_or(String::from("http://localhost:4000"));
+    let site_title = std::env::var("BLOG_TITLE").unwrap_or(String::from("Blog"));
+    let site_url = site_url.trim_end_matches('/').to_string();
+    let cookies = CookiePolicy::from_env(&site_url);
+    let oidc = OidcConfig::from_env(&site_url);
+    let links = MailLinks::from_env(&site_url);
+    let secret: Arc<[u8]> = match std::env::var("BLOG_SECRET") {
+        Ok(secret) => secret.into_bytes().into(),
+        Err(_) => {
+            eprintln!("BLOG_SECRET is not set, links sent before a restart will stop working.");
+            rand::thread_rng().gen::<[u8; 32]>().into()
+        }
+    };
+
+    let state = AppState {
         // client: client.clone(),
         // database: client.database("blog"),
-        posts_collection: client.database("blog").collection::<Post>("posts"),
+        posts_collection,
         users_collection: client.database("blog").collection::<User>("users"),
-        auths_collection: client.database("blog").collection::<Auth>("auths"),
-        sessions_collection: client.database("blog").collection::<Session>("sessions"),
-    });
+        auths_collection,
+        sessions_collection,
+        tags_collection,
+        post_revisions_collection,
+        comments_collection,
+        password_resets_collection,
+        magic_links_collection,
+        api_tokens_collection,
+        sign_in_throttles_collection,
+        request_limits_collection,
+        lockout_events_collection: client
+            .database("blog")
+            .collection::<LockoutEvent>("lockout_events"),
+        site_url,
+        site_title,
+        cookies,
+        secret,
+        mailer: mail::from_env(),
+        links,
+        passwords: PasswordHasher::from_env(),
+        password_policy: PasswordPolicy::from_env(),
+        restrictions: Restrictions::from_env(),
+        lockout: LockoutPolicy::from_env(),
+        oidc,
+        magic_link: MagicLinkConfig::from_env(),
+    };
+
+    let app = Router::new()
+        .nest("/api", make_api(state.clone()))
+        .merge(make_feeds())
+        .with_state(state);
 
     axum::Server::bind(&"0.0.0.0:4000".parse().unwrap())
-        .serve(app.into_make_service())
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
         .await
         .unwrap();
 
     Ok(())
 }
 
-fn make_api() -> Router<AppState> {
+fn make_api(state: AppState) -> Router<AppState> {
     Router::new()
         .route("/posts", post(Post::create))
         .route("/posts", get(Post::read_all))
         .route("/posts/:id", get(Post::read))
+        .route("/posts/by-slug/:slug", get(Post::read_by_slug))
+        .route("/posts/search", get(Post::search))
         .route("/posts/:id", patch(Post::update))
         .route("/posts/:id", delete(Post::delete))
+        .route("/posts/:id/publish", post(Post::publish))
+        .route("/posts/:id/unpublish", post(Post::unpublish))
+        .route("/posts/:id/archive", post(Post::archive))
+        .route("/posts/:id/revisions", get(Post::read_revisions))
+        .route("/posts/:id/revisions/diff", get(Post::diff_revisions))
+        .route("/posts/:id/revisions/:rev", get(Post::read_revision))
+        .route(
+            "/posts/:id/revisions/:rev/restore",
+            post(Post::restore_revision),
+        )
+        .route("/posts/:id/comments", post(Comment::create))
+        .route("/posts/:id/comments", get(Comment::read_all))
+        .route("/posts/:id/comments/:comment_id", patch(Comment::update))
+        .route("/posts/:id/comments/:comment_id", delete(Comment::delete))
+        .route("/tags", get(Tag::read_all))
+        .route("/tags/:name/posts", get(Post::read_by_tag))
+        .route("/tags/:name/rename", post(Tag::rename))
+        .route("/tags/:name/merge", post(Tag::merge))
         .route("/users", post(User::create))
         .route("/users", get(User::read_all))
+        .route("/users/roles", get(User::read_roles))
         .route("/users/:id", get(User::read))
         .route("/users/:id", patch(User::update))
         .route("/users/:id", delete(User::delete))
+        .route("/users/:id/role", put(User::change_role))
         .route("/auth", post(Auth::create))
         .route("/auth", get(Auth::read_all))
         .route("/auth/:id", get(Auth::read))
         .route("/auth/:id", patch(Auth::update))
         .route("/auth/:id", delete(Auth::delete))
+        .route("/auth/verify", post(Auth::verify))
+        .route("/auth/verify/resend", post(Auth::resend_verification))
+        .route(
+            "/auth/password-reset/request",
+            post(Auth::request_password_reset),
+        )
+        .route(
+            "/auth/password-reset/confirm",
+            post(Auth::confirm_password_reset),
+        )
         .route("/auth/sign-in", post(Auth::sign_in))
+        .route("/auth/magic-link", post(Auth::request_magic_link))
+        .route("/auth/magic-link/:token", get(Auth::consume_magic_link))
+        .route("/auth/oidc/login", get(Auth::oidc_login))
+        .route("/auth/oidc/callback", get(Auth::oidc_callback))
+        .route("/auth/2fa/verify", post(Auth::verify_two_factor))
+        .route("/auth/2fa/enroll", post(Auth::enroll_two_factor))
+        .route("/auth/2fa/confirm", post(Auth::confirm_two_factor))
+        .route("/auth/2fa/disable", post(Auth::disable_two_factor))
         .route("/auth/sign-in-session", post(Auth::sign_in_session))
+        .route("/auth/sign-out", post(Auth::sign_out))
+        .route("/auth/sign-out-everywhere", post(Auth::sign_out_everywhere))
+        .route("/auth/sessions", get(Session::read_all))
+        .route("/auth/sessions/:id", delete(Session::delete))
+        .route("/auth/tokens", post(ApiToken::create))
+        .route("/auth/tokens", get(ApiToken::read_all))
+        .route("/auth/tokens/:id", delete(ApiToken::delete))
+        .route("/auth/lockouts", get(LockoutEvent::read_all))
+        .route("/auth/lockouts/:id", delete(LockoutEvent::clear))
+        .route_layer(middleware::from_fn_with_state(state, cookie::protect))
+}
+
+fn make_feeds() -> Router<AppState> {
+    Router::new()
+        .route("/feed.xml", get(feed::rss))
+        .route("/atom.xml", get(feed::atom))
+        .route("/feed.json", get(feed::json))
 }
 
 async fn get_database_client() -> mongodb::error::Result<Client> {
@@ -0,0 +1,181 @@
+use crate::error::{AppError, AppResult};
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{self, PasswordHash, PasswordHasher as _, PasswordVerifier, SaltString};
+use argon2::{Algorithm, Argon2, Params, Version};
+use std::sync::OnceLock;
+
+/// Hash of a password nobody has, made by the first `PasswordHasher::verify_dummy`.
+static DUMMY_HASH: OnceLock<String> = OnceLock::new();
+
+/// How new password hashes are made. Read from `BLOG_PASSWORD_HASH`, either `argon2id`
+/// (the default) with `BLOG_ARGON2_MEMORY` (in KiB), `BLOG_ARGON2_ITERATIONS` and
+/// `BLOG_ARGON2_PARALLELISM`, or `bcrypt` with `BLOG_BCRYPT_COST`.
+///
+/// Stored hashes are verified whichever algorithm made them, telling them apart by
+/// their PHC or bcrypt prefix, so the setting can change at any time.
+#[derive(Clone, Debug)]
+pub(crate) enum PasswordHasher {
+    Bcrypt { cost: u32 },
+    Argon2id { params: Params },
+}
+
+impl PasswordHasher {
+    pub(crate) fn from_env() -> Self {
+        let number = |name: &str, default: u32| {
+            std::env::var(name)
+                .ok()
+                .and_then(|value| value.parse().ok())
+                .unwrap_or(default)
+        };
+
+        match std::env::var("BLOG_PASSWORD_HASH").as_deref() {
+            Ok("bcrypt") => PasswordHasher::Bcrypt {
+                cost: number("BLOG_BCRYPT_COST", bcrypt::DEFAULT_COST).clamp(4, 31),
+            },
+            _ => {
+                let params = Params::new(
+                    number("BLOG_ARGON2_MEMORY", Params::DEFAULT_M_COST),
+                    number("BLOG_ARGON2_ITERATIONS", Params::DEFAULT_T_COST),
+                    number("BLOG_ARGON2_PARALLELISM", Params::DEFAULT_P_COST),
+                    None,
+                );
+                let params = params.unwrap_or_else(|error| {
+                    eprintln!("Invalid Argon2 parameters ({error}), using the defaults.");
+                    Params::DEFAULT
+                });
+                PasswordHasher::Argon2id { params }
+            }
+        }
+    }
+
+    /// Hashes `password` on the blocking thread pool, hashing is slow on purpose.
+    pub(crate) async fn hash(&self, password: String) -> AppResult<String> {
+        let hasher = self.clone();
+        tokio::task::spawn_blocking(move || hasher.hash_blocking(&password))
+            .await
+            .map_err(|error| AppError::Internal(error.to_string()))?
+    }
+
+    /// Checks `password` against `stored`, on the blocking thread pool.
+    pub(crate) async fn verify(&self, password: String, stored: String) -> AppResult<bool> {
+        tokio::task::spawn_blocking(move || verify_blocking(&password, &stored))
+            .await
+            .map_err(|error| AppError::Internal(error.to_string()))?
+    }
+
+    /// Checks `password` against a hash nobody's password matches, for sign ins with
+    /// no hash to check, like unknown emails. Takes as long as `verify`, so response
+    /// times do not give away which emails have a password.
+    pub(crate) async fn verify_dummy(&self, password: String) -> AppResult<()> {
+        let hasher = self.clone();
+        tokio::task::spawn_blocking(move || {
+            let dummy = match DUMMY_HASH.get() {
+                Some(dummy) => dummy,
+                None => {
+                    let dummy = hasher.hash_blocking(&nanoid::nanoid!(32))?;
+                    DUMMY_HASH.get_or_init(|| dummy)
+                }
+            };
+            verify_blocking(&password, dummy).map(|_| ())
+        })
+        .await
+        .map_err(|error| AppError::Internal(error.to_string()))?
+    }
+
+    /// Whether `stored` was made with another algorithm, or other parameters, than
+    /// `PasswordHasher::hash` uses now.
+    pub(crate) fn needs_rehash(&self, stored: &str) -> bool {
+        match self {
+            PasswordHasher::Bcrypt { cost } => match stored.parse::<bcrypt::HashParts>() {
+                Ok(parts) => parts.get_cost() != *cost,
+                Err(_) => true,
+            },
+            PasswordHasher::Argon2id { params } => {
+                let Ok(hash) = PasswordHash::new(stored) else { return true };
+                let same_params = Params::try_from(&hash).is_ok_and(|stored| {
+                    stored.m_cost() == params.m_cost()
+                        && stored.t_cost() == params.t_cost()
+                        && stored.p_cost() == params.p_cost()
+                });
+                hash.algorithm != Algorithm::Argon2id.ident()
+                    || hash.version != Some(Version::V0x13.into())
+                    || !same_params
+            }
+        }
+    }
+
+    fn hash_blocking(&self, password: &str) -> AppResult<String> {
+        match self {
+            PasswordHasher::Bcrypt { cost } => Ok(bcrypt::hash(password, *cost)?),
+            PasswordHasher::Argon2id { params } => {
+                let salt = SaltString::generate(&mut OsRng);
+                let argon2 = Argon2::new(Algorithm::Argon2id, Version::V0x13, params.clone());
+                Ok(argon2.hash_password(password.as_bytes(), &salt)?.to_string())
+            }
+        }
+    }
+}
+
+fn verify_blocking(password: &str, stored: &str) -> AppResult<bool> {
+    if !stored.starts_with("$argon2") {
+        return Ok(bcrypt::verify(password, stored)?);
+    }
+
+    // the algorithm and parameters come from the stored hash
+    let hash = PasswordHash::new(stored)?;
+    match Argon2::default().verify_password(password.as_bytes(), &hash) {
+        Ok(()) => Ok(true),
+        Err(password_hash::Error::Password) => Ok(false),
+        Err(error) => Err(error.into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn argon2id(m_cost: u32) -> PasswordHasher {
+        PasswordHasher::Argon2id { params: Params::new(m_cost, 1, 1, None).unwrap() }
+    }
+
+    #[test]
+    fn fresh_hashes_need_no_rehash() {
+        for hasher in [argon2id(1024), PasswordHasher::Bcrypt { cost: 4 }] {
+            let stored = hasher.hash_blocking("password").unwrap();
+            assert!(!hasher.needs_rehash(&stored));
+            assert!(verify_blocking("password", &stored).unwrap());
+            assert!(!verify_blocking("wrong", &stored).unwrap());
+        }
+    }
+
+    #[test]
+    fn other_parameters_need_a_rehash() {
+        let stored = argon2id(1024).hash_blocking("password").unwrap();
+        assert!(argon2id(2048).needs_rehash(&stored));
+
+        let stored = PasswordHasher::Bcrypt { cost: 4 }.hash_blocking("password").unwrap();
+        assert!(PasswordHasher::Bcrypt { cost: 5 }.needs_rehash(&stored));
+    }
+
+    #[test]
+    fn other_algorithms_need_a_rehash() {
+        let bcrypt = PasswordHasher::Bcrypt { cost: 4 }.hash_blocking("password").unwrap();
+        let argon2 = argon2id(1024).hash_blocking("password").unwrap();
+
+        assert!(argon2id(1024).needs_rehash(&bcrypt));
+        assert!(PasswordHasher::Bcrypt { cost: 4 }.needs_rehash(&argon2));
+    }
+
+    #[test]
+    fn argon2i_hashes_need_a_rehash() {
+        let salt = SaltString::generate(&mut OsRng);
+        let params = Params::new(1024, 1, 1, None).unwrap();
+        let stored = Argon2::new(Algorithm::Argon2i, Version::V0x13, params)
+            .hash_password(b"password", &salt)
+            .unwrap()
+            .to_string();
+
+        assert!(argon2id(1024).needs_rehash(&stored));
+    }
+}
@@ -0,0 +1,134 @@
+use pulldown_cmark::{html, Options, Parser};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+pub enum ContentFormat {
+    #[default]
+    Markdown,
+    Html,
+    Plain,
+}
+
+/// Renders `content` written in `format` to HTML that is safe to embed in a page.
+pub(crate) fn render(format: ContentFormat, content: &str) -> String {
+    match format {
+        ContentFormat::Markdown => sanitize(&render_markdown(content)),
+        ContentFormat::Html => sanitize(content),
+        ContentFormat::Plain => render_plain(content),
+    }
+}
+
+fn render_markdown(content: &str) -> String {
+    let mut options = Options::empty();
+    options.insert(Options::ENABLE_TABLES);
+    options.insert(Options::ENABLE_FOOTNOTES);
+    options.insert(Options::ENABLE_TASKLISTS);
+    options.insert(Options::ENABLE_STRIKETHROUGH);
+
+    let mut rendered = String::with_capacity(content.len() * 3 / 2);
+    html::push_html(&mut rendered, Parser::new_ext(content, options));
+    rendered
+}
+
+/// Prefixes every `id` in rendered content, so posts cannot clobber the page's own
+/// elements through `document.<id>` or `window.<id>`.
+const ID_PREFIX: &str = "user-content-";
+
+/// Strips everything outside of ammonia's allowlist, plus the few attributes the
+/// Markdown extensions need for footnotes and task lists. Inputs are always
+/// disabled checkboxes, whatever they were written as, and in-page links follow
+/// the prefixed ids.
+fn sanitize(rendered: &str) -> String {
+    ammonia::Builder::default()
+        .add_tags(["input"])
+        .add_tag_attributes("input", ["checked"])
+        .set_tag_attribute_value("input", "type", "checkbox")
+        .set_tag_attribute_value("input", "disabled", "")
+        .add_tag_attributes("div", ["id"])
+        .id_prefix(Some(ID_PREFIX))
+        .attribute_filter(|element, attribute, value| match (element, attribute) {
+            ("a", "href") if value.starts_with('#') => Some(format!("#{ID_PREFIX}{}", &value[1..]).into()),
+            _ => Some(value.into()),
+        })
+        .add_allowed_classes("sup", ["footnote-reference", "footnote-definition-label"])
+        .add_allowed_classes("div", ["footnote-definition"])
+        .clean(rendered)
+        .to_string()
+}
+
+fn render_plain(content: &str) -> String {
+    content
+        .split("\n\n")
+        .filter(|paragraph| !paragraph.trim().is_empty())
+        .map(|paragraph| format!("<p>{}</p>\n", escape(paragraph.trim()).replace('\n', "<br>")))
+        .collect()
+}
+
+/// Escapes `text` for HTML and XML, in element content and quoted attributes alike.
+pub(crate) fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for character in text.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn markdown_strips_scripts_and_event_handlers() {
+        let html = render(ContentFormat::Markdown, "Hi <script>alert(1)</script><img src=x onerror=alert(1)>");
+        assert!(!html.contains("<script"));
+        assert!(!html.contains("onerror"));
+    }
+
+    #[test]
+    fn html_keeps_safe_markup() {
+        let html = render(ContentFormat::Html, "<p><strong>bold</strong> <a href=\"https://example.com\">link</a></p>");
+        assert!(html.contains("<strong>bold</strong>"));
+        assert!(html.contains("href=\"https://example.com\""));
+    }
+
+    #[test]
+    fn task_lists_render_disabled_checkboxes() {
+        let html = render(ContentFormat::Markdown, "- [x] done\n- [ ] todo");
+        assert_eq!(html.matches("type=\"checkbox\"").count(), 2);
+        assert_eq!(html.matches("disabled").count(), 2);
+        assert!(html.contains("checked"));
+    }
+
+    #[test]
+    fn inputs_of_other_types_become_disabled_checkboxes() {
+        for input in ["<input type=\"password\">", "<input type=\"submit\" value=\"Go\">", "<input>"] {
+            let html = render(ContentFormat::Html, input);
+            assert!(html.contains("type=\"checkbox\""), "{html}");
+            assert!(html.contains("disabled"), "{html}");
+            assert!(!html.contains("password") && !html.contains("submit") && !html.contains("value"), "{html}");
+        }
+    }
+
+    #[test]
+    fn ids_are_prefixed_and_footnote_links_follow() {
+        let html = render(ContentFormat::Markdown, "Text[^note]\n\n[^note]: The note.");
+        assert!(html.contains("id=\"user-content-note\""), "{html}");
+        assert!(html.contains("href=\"#user-content-note\""), "{html}");
+
+        let html = render(ContentFormat::Html, "<div id=\"location\">x</div>");
+        assert!(html.contains("id=\"user-content-location\""), "{html}");
+    }
+
+    #[test]
+    fn plain_text_is_escaped_into_paragraphs() {
+        let html = render(ContentFormat::Plain, "a < b\nc\n\n'd' & \"e\"");
+        assert_eq!(html, "<p>a &lt; b<br>c</p>\n<p>&#39;d&#39; &amp; &quot;e&quot;</p>\n");
+    }
+}
@@ -1,20 +1,58 @@
-use crate::session::Session;
+mod content;
+mod revision;
+mod search;
+
+use crate::api_token::Scope;
+use crate::error::{stored_id, AppError, AppResult};
+use crate::session::{Credentials, Session};
+use crate::tag::Tag;
+use crate::user::{Permission, User};
 use crate::utils::database;
+use crate::utils::query::{ListParams, Page};
+use crate::utils::slug::{self, slugify};
+use crate::view::{Json, View};
 use crate::AppState;
 
 use async_trait::async_trait;
 use axum::extract::{Path, State};
-use axum::headers::Cookie;
-use axum::http::StatusCode;
-use axum::{Json, TypedHeader};
+use axum::http::{HeaderMap, StatusCode};
+use axum::response::{IntoResponse, Redirect, Response};
 use chrono::{DateTime, Utc};
+pub use content::ContentFormat;
+pub(crate) use content::escape;
+pub(crate) use revision::PostRevision;
 use database::Crud;
 use mongodb::bson;
 use mongodb::bson::oid::ObjectId;
+use mongodb::bson::Document;
+use mongodb::options::{FindOneAndUpdateOptions, IndexOptions, ReturnDocument};
+use mongodb::{Collection, IndexModel};
 use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// How often the background task looks for scheduled posts that are due.
+const SCHEDULER_INTERVAL: Duration = Duration::from_secs(60);
 
-// needed to call .next() in mongodb Cursor type
-use futures::StreamExt;
+#[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+pub enum PostStatus {
+    Draft,
+    Scheduled,
+    // posts created before statuses existed were always public
+    #[default]
+    Published,
+    Archived,
+}
+
+impl PostStatus {
+    /// Status and publication date of a post published at `now`, asking for
+    /// `published_at`: a date in the future schedules it, anything else publishes now.
+    fn publishing(now: DateTime<Utc>, published_at: Option<DateTime<Utc>>) -> (PostStatus, DateTime<Utc>) {
+        match published_at {
+            Some(published_at) if published_at > now => (PostStatus::Scheduled, published_at),
+            _ => (PostStatus::Published, now),
+        }
+    }
+}
 
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct Post {
@@ -22,125 +60,707 @@ pub struct Post {
     pub id: Option<bson::oid::ObjectId>,
     pub author_id: ObjectId,
     pub title: String,
+    #[serde(default)]
+    pub slug: String,
+    /// Slugs this post was reachable under before its title changed.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub previous_slugs: Vec<String>,
+    pub content: String,
+    #[serde(default)]
+    pub content_format: ContentFormat,
+    /// `content` rendered and sanitized on write, safe to embed as is.
+    #[serde(default)]
+    pub content_html: String,
+    /// Normalized tag names, see `Tag::normalize`.
+    #[serde(default)]
+    pub tags: Vec<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    #[serde(default)]
+    pub status: PostStatus,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime<Utc>>,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+/// A post as served to readers, without the slug history.
+#[derive(Serialize, Debug)]
+pub(crate) struct PostView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub author_id: ObjectId,
+    pub title: String,
+    pub slug: String,
     pub content: String,
+    pub content_format: ContentFormat,
+    pub content_html: String,
+    pub tags: Vec<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub category: Option<String>,
+    pub status: PostStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub published_at: Option<DateTime<Utc>>,
     #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime<Utc>>,
     #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+impl View for PostView {}
+
+impl From<Post> for PostView {
+    fn from(mut post: Post) -> Self {
+        post.ensure_rendered();
+        PostView {
+            id: post.id,
+            author_id: post.author_id,
+            title: post.title,
+            slug: post.slug,
+            content: post.content,
+            content_format: post.content_format,
+            content_html: post.content_html,
+            tags: post.tags,
+            category: post.category,
+            status: post.status,
+            published_at: post.published_at,
+            created_at: post.created_at,
+            updated_at: post.updated_at,
+        }
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, PartialEq)]
 pub struct CreatePost {
     pub title: String,
     pub content: String,
+    #[serde(default)]
+    pub content_format: ContentFormat,
+    #[serde(default)]
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+}
+
+/// Changes to a post. Fields left out keep their current value, and an empty
+/// `category` removes it.
+#[derive(Deserialize, Debug, Default)]
+pub struct UpdatePost {
+    pub title: Option<String>,
+    pub content: Option<String>,
+    pub content_format: Option<ContentFormat>,
+    pub tags: Option<Vec<String>>,
+    pub category: Option<String>,
+}
+
+impl UpdatePost {
+    /// The fields of `current` with these changes applied.
+    fn merge(self, current: &Post) -> PostEdit {
+        let category = match self.category {
+            Some(category) => Some(category.trim().to_string()).filter(|category| !category.is_empty()),
+            None => current.category.clone(),
+        };
+
+        PostEdit {
+            title: self.title.unwrap_or_else(|| current.title.clone()),
+            content: self.content.unwrap_or_else(|| current.content.clone()),
+            content_format: self.content_format.unwrap_or(current.content_format),
+            tags: match self.tags {
+                Some(tags) => Tag::normalize(&tags),
+                None => current.tags.clone(),
+            },
+            category,
+        }
+    }
+}
+
+/// The editable fields of a post, with `tags` already normalized.
+pub(crate) struct PostEdit {
+    pub title: String,
+    pub content: String,
+    pub content_format: ContentFormat,
+    pub tags: Vec<String>,
+    pub category: Option<String>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PublishPost {
+    /// Publishes at this moment instead of immediately, if it lies in the future.
+    pub published_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+pub struct PostFilter {
+    pub author_id: Option<ObjectId>,
+    pub status: Option<PostStatus>,
+    pub tag: Option<String>,
+    pub category: Option<String>,
+}
+
+impl Post {
+    /// Matches the posts `auth_id` is allowed to read: every published post, plus
+    /// all of the caller's own posts regardless of their status.
+    pub(crate) fn visible_to(auth_id: Option<ObjectId>) -> Document {
+        let mut visible = vec![
+            bson::doc! { "status": "Published" },
+            bson::doc! { "status": { "$exists": false } },
+        ];
+        if let Some(auth_id) = auth_id {
+            visible.push(bson::doc! { "author_id": auth_id });
+        }
+        bson::doc! { "$or": visible }
+    }
+
+    /// Creates the indexes the posts collection relies on. Meant to be called once
+    /// from `main` on startup.
+    pub(crate) async fn create_indexes(
+        posts_collection: &Collection<Post>,
+    ) -> mongodb::error::Result<()> {
+        // posts created before slugs existed have none, so only index actual strings
+        let slug_index = IndexModel::builder()
+            .keys(bson::doc! { "slug": 1 })
+            .options(
+                IndexOptions::builder()
+                    .unique(true)
+                    .partial_filter_expression(bson::doc! { "slug": { "$type": "string" } })
+                    .build(),
+            )
+            .build();
+        let previous_slugs_index = IndexModel::builder()
+            .keys(bson::doc! { "previous_slugs": 1 })
+            .build();
+        let text_index = IndexModel::builder()
+            .keys(bson::doc! { "title": "text", "content": "text" })
+            .options(
+                IndexOptions::builder()
+                    .name(String::from("posts_text"))
+                    .weights(bson::doc! { "title": 10, "content": 1 })
+                    .build(),
+            )
+            .build();
+        posts_collection
+            .create_indexes([slug_index, previous_slugs_index, text_index], None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Derives a slug from `title` that no other post currently uses or used to use,
+    /// appending `-2`, `-3`, ... on collisions. `id` is the post being renamed, whose
+    /// own slugs are free to reuse.
+    async fn unique_slug(
+        posts_collection: &Collection<Post>,
+        title: &str,
+        id: Option<ObjectId>,
+    ) -> mongodb::error::Result<String> {
+        for slug in slug::candidates(title, "post") {
+            let taken = posts_collection
+                .count_documents(
+                    bson::doc! {
+                        "_id": { "$ne": id },
+                        "$or": [{ "slug": &slug }, { "previous_slugs": &slug }],
+                    },
+                    None,
+                )
+                .await?;
+            if taken == 0 {
+                return Ok(slug);
+            }
+        }
+
+        unreachable!("slug candidates never run out")
+    }
+
+    /// Resolves a post by its current slug, or redirects to the current slug when
+    /// `slug` is one the post used to have.
+    pub(crate) async fn read_by_slug(
+        credentials: Credentials,
+        Path(slug): Path<String>,
+        State(state): State<AppState>,
+    ) -> AppResult<Response> {
+        let auth = Session::auth(&credentials, &state, Scope::PostsRead).await?;
+
+        let post = state
+            .posts_collection
+            .find_one(
+                bson::doc! { "$or": [{ "slug": &slug }, { "previous_slugs": &slug }] },
+                None,
+            )
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        if !post.is_visible_to(auth.and_then(|auth| auth.id)) {
+            return Err(AppError::NotFound);
+        }
+
+        if post.slug != slug {
+            return Ok(
+                Redirect::permanent(&format!("/api/posts/by-slug/{}", post.slug)).into_response(),
+            );
+        }
+
+        Ok((StatusCode::FOUND, Json(PostView::from(post))).into_response())
+    }
+
+    pub(crate) async fn read_by_tag(
+        credentials: Credentials,
+        Path(tag): Path<String>,
+        mut params: ListParams<PostFilter>,
+        state: State<AppState>,
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<PostView>>)> {
+        params.filter.tag = Some(tag);
+        Post::read_all(credentials, params, state).await
+    }
+
+    /// Applies `edit` to `current` on behalf of `editor`: keeps the replaced version as
+    /// a revision, moves the slug along with the title and updates the tag counts.
+    async fn edit(
+        state: &AppState,
+        current: Post,
+        edit: PostEdit,
+        editor: ObjectId,
+    ) -> AppResult<Post> {
+        let id = stored_id(current.id)?;
+
+        PostRevision::record(&state.post_revisions_collection, &current, &edit, editor).await?;
+
+        let mut slug = current.slug.clone();
+        let mut previous_slugs = current.previous_slugs.clone();
+        if current.title != edit.title || current.slug.is_empty() {
+            let new_slug = Post::unique_slug(&state.posts_collection, &edit.title, Some(id)).await?;
+            if new_slug != slug {
+                if !slug.is_empty() {
+                    previous_slugs.push(slug);
+                }
+                previous_slugs.retain(|previous| previous != &new_slug);
+                slug = new_slug;
+            }
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let post = state
+            .posts_collection
+            .find_one_and_update(
+                bson::doc! { "_id": id },
+                bson::doc! { "$set": {
+                    "title": &edit.title,
+                    "content_html": content::render(edit.content_format, &edit.content),
+                    "content": &edit.content,
+                    "content_format": bson::to_bson(&edit.content_format)?,
+                    "slug": slug,
+                    "previous_slugs": previous_slugs,
+                    "tags": &edit.tags,
+                    "category": &edit.category,
+                    "updatedAt": bson::to_bson(&Utc::now())?,
+                } },
+                options,
+            )
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        let changed: Vec<String> = edit.tags.iter()
+            .filter(|tag| !current.tags.contains(tag))
+            .chain(current.tags.iter().filter(|tag| !edit.tags.contains(tag)))
+            .cloned()
+            .collect();
+        Tag::recount(&state.posts_collection, &state.tags_collection, &changed).await?;
+
+        Ok(post)
+    }
+
+    /// Fills in `content_html` for posts stored before it was rendered on write.
+    pub(crate) fn ensure_rendered(&mut self) {
+        if self.content_html.is_empty() && !self.content.is_empty() {
+            self.content_html = content::render(self.content_format, &self.content);
+        }
+    }
+
+    pub(crate) fn is_visible_to(&self, auth_id: Option<ObjectId>) -> bool {
+        self.status == PostStatus::Published || Some(self.author_id) == auth_id
+    }
+
+    /// The user behind `credentials` if their role grants `permission` and post `id`
+    /// is theirs, or they may edit anyone's, with a filter for the post.
+    async fn editable(
+        credentials: &Credentials,
+        id: ObjectId,
+        state: &AppState,
+        scope: Scope,
+        permission: Permission,
+    ) -> AppResult<(User, Document)> {
+        let user = Session::authorize(credentials, state, scope, permission).await?;
+        let post = state
+            .posts_collection
+            .find_one(bson::doc! { "_id": id }, None)
+            .await?;
+        // someone else's post stays hidden, it may be a draft
+        let post = post.filter(|post| user.owns_or_can(post.author_id == user.auth_id, Permission::PostsEditAny));
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        Ok((user, bson::doc! { "_id": id, "author_id": post.author_id }))
+    }
+
+    async fn set_status(
+        credentials: &Credentials,
+        id: ObjectId,
+        state: &AppState,
+        update: Document,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let (_, filter) = Post::editable(credentials, id, state, Scope::PostsWrite, Permission::PostsPublish).await?;
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let post = state
+            .posts_collection
+            .find_one_and_update(filter, bson::doc! { "$set": update }, options)
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        Tag::recount(&state.posts_collection, &state.tags_collection, &post.tags).await?;
+
+        Ok((StatusCode::OK, Json(PostView::from(post))))
+    }
+
+    pub(crate) async fn publish(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+        json: Option<Json<PublishPost>>,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let now = Utc::now();
+        let (status, published_at) = PostStatus::publishing(now, json.and_then(|Json(json)| json.published_at));
+
+        Post::set_status(
+            &credentials,
+            id,
+            &state,
+            bson::doc! {
+                "status": bson::to_bson(&status)?,
+                "published_at": bson::to_bson(&published_at)?,
+                "updatedAt": bson::to_bson(&now)?,
+            },
+        )
+        .await
+    }
+
+    pub(crate) async fn unpublish(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        Post::set_status(
+            &credentials,
+            id,
+            &state,
+            bson::doc! {
+                "status": "Draft",
+                "published_at": null,
+                "updatedAt": bson::to_bson(&Utc::now())?,
+            },
+        )
+        .await
+    }
+
+    pub(crate) async fn archive(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        Post::set_status(
+            &credentials,
+            id,
+            &state,
+            bson::doc! { "status": "Archived", "updatedAt": bson::to_bson(&Utc::now())? },
+        )
+        .await
+    }
+
+    /// Flips every scheduled post whose `published_at` has passed to published and
+    /// recounts the tags those posts carry.
+    pub(crate) async fn publish_scheduled(
+        posts_collection: &Collection<Post>,
+        tags_collection: &Collection<Tag>,
+    ) -> mongodb::error::Result<u64> {
+        let now = Utc::now();
+        let due = Post::due(now)?;
+        let now = bson::to_bson(&now)?;
+        let tags: Vec<String> = posts_collection
+            .distinct("tags", due.clone(), None)
+            .await?
+            .into_iter()
+            .filter_map(|tag| tag.as_str().map(String::from))
+            .collect();
+        let result = posts_collection
+            .update_many(due, bson::doc! { "$set": { "status": "Published", "updatedAt": &now } }, None)
+            .await?;
+        Tag::recount(posts_collection, tags_collection, &tags).await?;
+
+        Ok(result.modified_count)
+    }
+
+    /// Matches the scheduled posts whose `published_at` is `now` or earlier.
+    fn due(now: DateTime<Utc>) -> bson::ser::Result<Document> {
+        Ok(bson::doc! { "status": "Scheduled", "published_at": { "$lte": bson::to_bson(&now)? } })
+    }
+
+    /// Runs `publish_scheduled` forever, meant to be spawned once from `main`.
+    pub(crate) async fn run_scheduler(posts_collection: Collection<Post>, tags_collection: Collection<Tag>) {
+        let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+        loop {
+            interval.tick().await;
+            if let Err(error) = Post::publish_scheduled(&posts_collection, &tags_collection).await {
+                eprintln!("Failed to publish scheduled posts: {error}");
+            }
+        }
+    }
 }
 
 #[async_trait]
-impl Crud<CreatePost, Post> for Post {
+impl Crud<CreatePost, UpdatePost> for Post {
+    type Filter = PostFilter;
+    type View = PostView;
+
     async fn create(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         State(state): State<AppState>,
         Json(json): Json<CreatePost>,
-    ) -> (StatusCode, Json<Option<Post>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let (_, auth) = Session::authorize_account(&credentials, &state, Scope::PostsWrite, Permission::PostsCreate).await?;
+        auth.ensure_verified(state.restrictions.posts)?;
+
+        let slug = Post::unique_slug(&state.posts_collection, &json.title, None).await?;
 
         let now = Utc::now();
         let mut post = Post {
             id: None,
-            author_id: auth.id.expect("User has no id"),
+            author_id: stored_id(auth.id)?,
             title: json.title,
+            slug,
+            previous_slugs: vec![],
+            content_html: content::render(json.content_format, &json.content),
             content: json.content,
+            content_format: json.content_format,
+            tags: Tag::normalize(&json.tags),
+            category: json.category.map(|category| category.trim().to_string()).filter(|category| !category.is_empty()),
+            status: PostStatus::Draft,
+            published_at: None,
             created_at: Some(now),
             updated_at: Some(now),
         };
 
-        let query = state.posts_collection.insert_one(&post, None).await;
+        let document = state.posts_collection.insert_one(&post, None).await?;
+        post.id = document.inserted_id.as_object_id();
 
-        match query {
-            Ok(document) => {
-                let id = document.inserted_id;
-                post.id = id.as_object_id();
-                (StatusCode::CREATED, Json(Some(post)))
-            }
-            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(None)),
-        }
+        Ok((StatusCode::CREATED, Json(PostView::from(post))))
     }
 
     async fn read_all(
-        _: TypedHeader<Cookie>,
+        credentials: Credentials,
+        params: ListParams<PostFilter>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Vec<Post>>>) {
-        let posts_cursor_query = state.posts_collection.find(None, None).await;
-        let Ok(mut posts_cursor) = posts_cursor_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-
-        let mut posts: Vec<Post> = vec![];
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<PostView>>)> {
+        let auth = Session::auth(&credentials, &state, Scope::PostsRead).await?;
 
-        while let Some(post_result) = posts_cursor.next().await {
-            if let Ok(post) = post_result {
-                posts.push(post);
-            }
+        let mut filter = Post::visible_to(auth.and_then(|auth| auth.id));
+        if let Some(author_id) = params.filter.author_id {
+            filter.insert("author_id", author_id);
+        }
+        if let Some(tag) = &params.filter.tag {
+            filter.insert("tags", slugify(tag));
+        }
+        if let Some(category) = &params.filter.category {
+            filter.insert("category", category.trim());
         }
+        if let Some(status) = params.filter.status {
+            filter = bson::doc! { "$and": [filter, { "status": bson::to_bson(&status)? }] };
+        }
+
+        let page = params.find_page(&state.posts_collection, filter).await?;
 
-        (StatusCode::FOUND, Json(Some(posts)))
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(PostView::from))))
     }
 
     async fn read(
-        TypedHeader(_): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<bson::oid::ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Post>>) {
-        let post_query = state
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let auth = Session::auth(&credentials, &state, Scope::PostsRead).await?;
+
+        let post = state
             .posts_collection
             .find_one(bson::doc! { "_id": id }, None)
-            .await;
-        let Ok(post) = post_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(post) = post else { return (StatusCode::NOT_FOUND, Json(None)) };
+            .await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
 
-        (StatusCode::FOUND, Json(Some(post)))
+        if !post.is_visible_to(auth.and_then(|auth| auth.id)) {
+            return Err(AppError::NotFound);
+        }
+
+        Ok((StatusCode::FOUND, Json(PostView::from(post))))
     }
 
     async fn update(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<bson::oid::ObjectId>,
         State(state): State<AppState>,
-        Json(json): Json<Post>,
-    ) -> (StatusCode, Json<Option<Post>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+        Json(json): Json<UpdatePost>,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let (user, filter) = Post::editable(&credentials, id, &state, Scope::PostsWrite, Permission::PostsCreate).await?;
 
-        let post_query = state
-            .posts_collection
-            .find_one_and_update(
-                bson::doc! { "_id": id, "author_id": auth.id },
-                bson::doc! { "$set": { "title": json.title, "content": json.content } },
-                None,
-            )
-            .await;
-        let Ok(post) = post_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(post) = post else { return (StatusCode::NOT_FOUND, Json(None)) };
+        let current = state.posts_collection.find_one(filter, None).await?;
+        let Some(current) = current else { return Err(AppError::NotFound) };
+
+        let edit = json.merge(&current);
+        let post = Post::edit(&state, current, edit, user.auth_id).await?;
 
-        (StatusCode::OK, Json(Some(post)))
+        Ok((StatusCode::OK, Json(PostView::from(post))))
     }
 
     async fn delete(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<bson::oid::ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Post>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let (_, filter) = Post::editable(&credentials, id, &state, Scope::PostsWrite, Permission::PostsCreate).await?;
 
-        let post_query = state
-            .posts_collection
-            .find_one_and_delete(bson::doc! { "_id": id, "author_id": auth.id }, None)
-            .await;
-        let Ok(post) = post_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(post) = post else { return (StatusCode::NOT_FOUND, Json(None)) };
+        let post = state.posts_collection.find_one_and_delete(filter, None).await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        Tag::recount(&state.posts_collection, &state.tags_collection, &post.tags).await?;
+
+        Ok((StatusCode::OK, Json(PostView::from(post))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn post() -> Post {
+        Post {
+            id: Some(ObjectId::new()),
+            author_id: ObjectId::new(),
+            title: String::from("Title"),
+            slug: String::from("title"),
+            previous_slugs: vec![],
+            content: String::from("<p>Body</p>"),
+            content_format: ContentFormat::Html,
+            content_html: String::from("<p>Body</p>"),
+            tags: vec![String::from("rust")],
+            category: Some(String::from("news")),
+            status: PostStatus::Draft,
+            published_at: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    #[test]
+    fn update_keeps_the_fields_left_out() {
+        let update = UpdatePost {
+            title: Some(String::from("New title")),
+            ..UpdatePost::default()
+        };
+        let edit = update.merge(&post());
+
+        assert_eq!(edit.title, "New title");
+        assert_eq!(edit.content, "<p>Body</p>");
+        assert_eq!(edit.content_format, ContentFormat::Html);
+        assert_eq!(edit.tags, ["rust"]);
+        assert_eq!(edit.category.as_deref(), Some("news"));
+    }
+
+    #[test]
+    fn update_normalizes_tags_and_clears_an_empty_category() {
+        let update = UpdatePost {
+            tags: Some(vec![String::from("Web Dev"), String::from("web-dev")]),
+            category: Some(String::from("  ")),
+            ..UpdatePost::default()
+        };
+        let edit = update.merge(&post());
+
+        assert_eq!(edit.tags, ["web-dev"]);
+        assert_eq!(edit.category, None);
+    }
+
+    #[test]
+    fn published_posts_are_visible_to_everyone() {
+        let post = Post { status: PostStatus::Published, ..post() };
+
+        assert!(post.is_visible_to(None));
+        assert!(post.is_visible_to(Some(ObjectId::new())));
+        assert!(post.is_visible_to(Some(post.author_id)));
+    }
+
+    #[test]
+    fn unpublished_posts_are_only_visible_to_their_author() {
+        for status in [PostStatus::Draft, PostStatus::Scheduled, PostStatus::Archived] {
+            let post = Post { status, ..post() };
+
+            assert!(!post.is_visible_to(None), "{status:?}");
+            assert!(!post.is_visible_to(Some(ObjectId::new())), "{status:?}");
+            assert!(post.is_visible_to(Some(post.author_id)), "{status:?}");
+        }
+    }
+
+    #[test]
+    fn visible_to_matches_the_author_only_when_signed_in() {
+        let auth_id = ObjectId::new();
+        let published = bson::doc! { "status": "Published" };
+        let legacy = bson::doc! { "status": { "$exists": false } };
+
+        assert_eq!(Post::visible_to(None), bson::doc! { "$or": [&published, &legacy] });
+        assert_eq!(
+            Post::visible_to(Some(auth_id)),
+            bson::doc! { "$or": [published, legacy, { "author_id": auth_id }] }
+        );
+    }
+
+    #[test]
+    fn posts_stored_without_a_status_read_as_published() {
+        let document = bson::doc! {
+            "_id": ObjectId::new(),
+            "author_id": ObjectId::new(),
+            "title": "Old post",
+            "content": "Written before statuses",
+        };
+        let post: Post = bson::from_document(document).unwrap();
+
+        assert_eq!(post.status, PostStatus::Published);
+        assert!(post.is_visible_to(None));
+    }
+
+    #[test]
+    fn publishing_without_a_date_or_with_a_past_one_publishes_now() {
+        let now = Utc::now();
+
+        assert_eq!(PostStatus::publishing(now, None), (PostStatus::Published, now));
+        assert_eq!(
+            PostStatus::publishing(now, Some(now - chrono::Duration::hours(1))),
+            (PostStatus::Published, now)
+        );
+    }
+
+    #[test]
+    fn publishing_with_a_future_date_schedules_the_post() {
+        let now = Utc::now();
+        let later = now + chrono::Duration::hours(1);
+
+        assert_eq!(PostStatus::publishing(now, Some(later)), (PostStatus::Scheduled, later));
+    }
+
+    #[test]
+    fn the_scheduler_publishes_scheduled_posts_that_are_due() {
+        let now = Utc::now();
 
-        (StatusCode::OK, Json(Some(post)))
+        assert_eq!(
+            Post::due(now).unwrap(),
+            bson::doc! { "status": "Scheduled", "published_at": { "$lte": bson::to_bson(&now).unwrap() } }
+        );
     }
 }
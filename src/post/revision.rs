@@ -0,0 +1,412 @@
+use super::{ContentFormat, Post, PostEdit, PostView};
+use crate::api_token::Scope;
+use crate::error::{AppError, AppResult};
+use crate::session::Credentials;
+use crate::user::Permission;
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Path, Query, State};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+use similar::TextDiff;
+
+/// A version of a post as it was before one of its edits.
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub(crate) struct PostRevision {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub post_id: ObjectId,
+    /// Sequential per post, starting at 1.
+    pub revision: i64,
+    /// Auth that made the edit replacing this version.
+    pub author_id: ObjectId,
+    pub title: String,
+    pub content: String,
+    #[serde(default)]
+    pub content_format: ContentFormat,
+    pub summary: String,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct DiffQuery {
+    pub from: i64,
+    /// Compares against the current post when left out.
+    pub to: Option<i64>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct RevisionDiff {
+    pub from: i64,
+    pub to: Option<i64>,
+    pub title_from: String,
+    pub title_to: String,
+    /// Unified diff of the content.
+    pub content: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct PostRevisionView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub post_id: ObjectId,
+    pub revision: i64,
+    pub author_id: ObjectId,
+    pub title: String,
+    pub content: String,
+    pub content_format: ContentFormat,
+    pub summary: String,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl View for PostRevisionView {}
+
+impl From<PostRevision> for PostRevisionView {
+    fn from(revision: PostRevision) -> Self {
+        PostRevisionView {
+            id: revision.id,
+            post_id: revision.post_id,
+            revision: revision.revision,
+            author_id: revision.author_id,
+            title: revision.title,
+            content: revision.content,
+            content_format: revision.content_format,
+            summary: revision.summary,
+            created_at: revision.created_at,
+        }
+    }
+}
+
+impl View for RevisionDiff {}
+
+impl PostRevision {
+    pub(crate) async fn create_indexes(
+        post_revisions_collection: &Collection<PostRevision>,
+    ) -> mongodb::error::Result<()> {
+        let revision_index = IndexModel::builder()
+            .keys(bson::doc! { "post_id": 1, "revision": -1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        post_revisions_collection
+            .create_index(revision_index, None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Stores `current` as the newest revision of its post, right before `edit`
+    /// replaces it.
+    pub(crate) async fn record(
+        post_revisions_collection: &Collection<PostRevision>,
+        current: &Post,
+        edit: &PostEdit,
+        author_id: ObjectId,
+    ) -> mongodb::error::Result<()> {
+        let Some(post_id) = current.id else { return Ok(()) };
+
+        let latest = post_revisions_collection
+            .find_one(
+                bson::doc! { "post_id": post_id },
+                FindOneOptions::builder()
+                    .sort(bson::doc! { "revision": -1 })
+                    .build(),
+            )
+            .await?;
+        let revision = next_revision(latest.as_ref());
+
+        post_revisions_collection
+            .insert_one(
+                PostRevision {
+                    id: None,
+                    post_id,
+                    revision,
+                    author_id,
+                    title: current.title.clone(),
+                    content: current.content.clone(),
+                    content_format: current.content_format,
+                    summary: summarize(current, edit),
+                    created_at: Some(Utc::now()),
+                },
+                None,
+            )
+            .await?;
+
+        Ok(())
+    }
+}
+
+/// Number of the revision following `latest`, 1 for a post's first one.
+fn next_revision(latest: Option<&PostRevision>) -> i64 {
+    latest.map(|latest| latest.revision).unwrap_or(0) + 1
+}
+
+/// Unified diff of revision `from`'s content against revision `to`, or against the
+/// current post when `to` is `None`.
+fn content_diff(from: i64, from_content: &str, to: Option<i64>, to_content: &str) -> String {
+    let from_label = format!("revision {from}");
+    let to_label = match to {
+        Some(to) => format!("revision {to}"),
+        None => String::from("current"),
+    };
+    TextDiff::from_lines(from_content, to_content)
+        .unified_diff()
+        .header(&from_label, &to_label)
+        .to_string()
+}
+
+/// Edit bringing `post` back to `revision`. Tags and category aren't part of
+/// revisions, so the post keeps its current ones.
+fn restore(post: &Post, revision: PostRevision) -> PostEdit {
+    PostEdit {
+        title: revision.title,
+        content: revision.content,
+        content_format: revision.content_format,
+        tags: post.tags.clone(),
+        category: post.category.clone(),
+    }
+}
+
+/// Describes how `edit` changes `current`, e.g. "title changed, content +3 -1 lines".
+fn summarize(current: &Post, edit: &PostEdit) -> String {
+    let mut changes = vec![];
+    if current.title != edit.title {
+        changes.push(String::from("title changed"));
+    }
+    if current.content != edit.content {
+        let diff = TextDiff::from_lines(&current.content, &edit.content);
+        let (mut inserted, mut deleted) = (0, 0);
+        for change in diff.iter_all_changes() {
+            match change.tag() {
+                similar::ChangeTag::Insert => inserted += 1,
+                similar::ChangeTag::Delete => deleted += 1,
+                similar::ChangeTag::Equal => (),
+            }
+        }
+        changes.push(format!("content +{inserted} -{deleted} lines"));
+    }
+    if current.content_format != edit.content_format {
+        changes.push(String::from("format changed"));
+    }
+    if current.tags != edit.tags || current.category != edit.category {
+        changes.push(String::from("taxonomy changed"));
+    }
+    if changes.is_empty() {
+        return String::from("no changes");
+    }
+    changes.join(", ")
+}
+
+impl Post {
+    /// Loads post `id` if `credentials` may edit it, which is also who may see and
+    /// restore its revisions.
+    async fn owned(
+        credentials: &Credentials,
+        id: ObjectId,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<(ObjectId, Post)> {
+        let (user, filter) = Post::editable(credentials, id, state, scope, Permission::PostsCreate).await?;
+
+        let post = state.posts_collection.find_one(filter, None).await?;
+        let Some(post) = post else { return Err(AppError::NotFound) };
+
+        Ok((user.auth_id, post))
+    }
+
+    async fn find_revision(state: &AppState, id: ObjectId, revision: i64) -> AppResult<PostRevision> {
+        let revision = state
+            .post_revisions_collection
+            .find_one(bson::doc! { "post_id": id, "revision": revision }, None)
+            .await?;
+        let Some(revision) = revision else { return Err(AppError::NotFound) };
+
+        Ok(revision)
+    }
+
+    pub(crate) async fn read_revisions(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        params: ListParams<NoFilter>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<PostRevisionView>>)> {
+        Post::owned(&credentials, id, &state, Scope::PostsRead).await?;
+
+        let page = params
+            .find_page(&state.post_revisions_collection, bson::doc! { "post_id": id })
+            .await?;
+
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(PostRevisionView::from))))
+    }
+
+    pub(crate) async fn read_revision(
+        credentials: Credentials,
+        Path((id, revision)): Path<(ObjectId, i64)>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<PostRevisionView>)> {
+        Post::owned(&credentials, id, &state, Scope::PostsRead).await?;
+        let revision = Post::find_revision(&state, id, revision).await?;
+
+        Ok((StatusCode::FOUND, Json(PostRevisionView::from(revision))))
+    }
+
+    pub(crate) async fn diff_revisions(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        Query(query): Query<DiffQuery>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<RevisionDiff>)> {
+        let (_, post) = Post::owned(&credentials, id, &state, Scope::PostsRead).await?;
+
+        let from = Post::find_revision(&state, id, query.from).await?;
+        let (title_to, content_to) = match query.to {
+            Some(to) => {
+                let to = Post::find_revision(&state, id, to).await?;
+                (to.title, to.content)
+            }
+            None => (post.title, post.content),
+        };
+
+        let content = content_diff(query.from, &from.content, query.to, &content_to);
+
+        Ok((
+            StatusCode::OK,
+            Json(RevisionDiff {
+                from: query.from,
+                to: query.to,
+                title_from: from.title,
+                title_to,
+                content,
+            }),
+        ))
+    }
+
+    /// Brings back the title, content and format of a revision. The version being
+    /// replaced is kept as a new revision, so a restore can itself be undone.
+    pub(crate) async fn restore_revision(
+        credentials: Credentials,
+        Path((id, revision)): Path<(ObjectId, i64)>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<PostView>)> {
+        let (auth_id, post) = Post::owned(&credentials, id, &state, Scope::PostsWrite).await?;
+        let revision = Post::find_revision(&state, id, revision).await?;
+
+        let edit = restore(&post, revision);
+        let post = Post::edit(&state, post, edit, auth_id).await?;
+
+        Ok((StatusCode::OK, Json(PostView::from(post))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::post::PostStatus;
+
+    fn post() -> Post {
+        Post {
+            id: Some(ObjectId::new()),
+            author_id: ObjectId::new(),
+            title: String::from("Title"),
+            slug: String::from("title"),
+            previous_slugs: vec![],
+            content: String::from("one\ntwo\nthree\n"),
+            content_format: ContentFormat::Markdown,
+            content_html: String::new(),
+            tags: vec![String::from("rust")],
+            category: Some(String::from("news")),
+            status: PostStatus::Draft,
+            published_at: None,
+            created_at: None,
+            updated_at: None,
+        }
+    }
+
+    fn edit_of(post: &Post) -> PostEdit {
+        PostEdit {
+            title: post.title.clone(),
+            content: post.content.clone(),
+            content_format: post.content_format,
+            tags: post.tags.clone(),
+            category: post.category.clone(),
+        }
+    }
+
+    fn revision(number: i64) -> PostRevision {
+        PostRevision {
+            id: None,
+            post_id: ObjectId::new(),
+            revision: number,
+            author_id: ObjectId::new(),
+            title: String::from("Old title"),
+            content: String::from("old\n"),
+            content_format: ContentFormat::Html,
+            summary: String::from("title changed"),
+            created_at: None,
+        }
+    }
+
+    #[test]
+    fn summarize_reports_no_changes() {
+        let post = post();
+
+        assert_eq!(summarize(&post, &edit_of(&post)), "no changes");
+    }
+
+    #[test]
+    fn summarize_lists_every_kind_of_change() {
+        let post = post();
+        let edit = PostEdit {
+            title: String::from("New title"),
+            content: String::from("one\n2\nthree\nfour\n"),
+            content_format: ContentFormat::Html,
+            tags: vec![],
+            ..edit_of(&post)
+        };
+
+        assert_eq!(
+            summarize(&post, &edit),
+            "title changed, content +2 -1 lines, format changed, taxonomy changed"
+        );
+    }
+
+    #[test]
+    fn revisions_are_numbered_from_one() {
+        assert_eq!(next_revision(None), 1);
+        assert_eq!(next_revision(Some(&revision(3))), 4);
+    }
+
+    #[test]
+    fn diff_against_the_current_post() {
+        let diff = content_diff(2, "one\ntwo\n", None, "one\n2\n");
+
+        assert_eq!(diff, "--- revision 2\n+++ current\n@@ -1,2 +1,2 @@\n one\n-two\n+2\n");
+    }
+
+    #[test]
+    fn diff_between_two_revisions() {
+        let diff = content_diff(1, "one\n", Some(3), "one\ntwo\n");
+
+        assert_eq!(diff, "--- revision 1\n+++ revision 3\n@@ -1 +1,2 @@\n one\n+two\n");
+    }
+
+    #[test]
+    fn restore_brings_back_the_text_and_keeps_the_taxonomy() {
+        let post = post();
+        let edit = restore(&post, revision(1));
+
+        assert_eq!(edit.title, "Old title");
+        assert_eq!(edit.content, "old\n");
+        assert_eq!(edit.content_format, ContentFormat::Html);
+        assert_eq!(edit.tags, ["rust"]);
+        assert_eq!(edit.category.as_deref(), Some("news"));
+    }
+}
@@ -0,0 +1,259 @@
+use super::content::escape;
+use super::{Post, PostView};
+use crate::error::{AppError, AppResult};
+use crate::api_token::Scope;
+use crate::session::{Credentials, Session};
+use crate::utils::query::page_limit;
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Query, State};
+use axum::http::StatusCode;
+use mongodb::bson::{self, Document};
+use mongodb::options::FindOptions;
+use serde::{Deserialize, Serialize};
+
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+/// Characters of context kept before the first match in a snippet.
+const SNIPPET_LEAD: usize = 60;
+const SNIPPET_LENGTH: usize = 200;
+
+#[derive(Deserialize, Debug)]
+pub(crate) struct SearchQuery {
+    pub q: String,
+    pub limit: Option<u32>,
+    pub offset: Option<u64>,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct SearchHit {
+    pub post: PostView,
+    pub score: f64,
+    /// Excerpt of the content around the first match, HTML escaped, with every
+    /// matching word wrapped in `<mark>`.
+    pub snippet: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct SearchResults {
+    pub items: Vec<SearchHit>,
+    /// Offset to pass as `?offset=` to fetch the following page.
+    pub next: Option<u64>,
+}
+
+impl View for SearchResults {}
+
+impl Post {
+    /// Ranks the posts visible to the caller by MongoDB text score over `title` and
+    /// `content`, see the text index in `Post::create_indexes`.
+    pub(crate) async fn search(
+        credentials: Credentials,
+        Query(query): Query<SearchQuery>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<SearchResults>)> {
+        let terms = search_terms(&query.q);
+        if terms.is_empty() {
+            return Err(AppError::BadRequest(String::from("The search query has no terms.")));
+        }
+
+        let auth = Session::auth(&credentials, &state, Scope::PostsRead).await?;
+
+        let limit = page_limit(query.limit);
+        let offset = query.offset.unwrap_or(0);
+        let options = FindOptions::builder()
+            .projection(bson::doc! { "score": { "$meta": "textScore" } })
+            .sort(bson::doc! { "score": { "$meta": "textScore" }, "_id": 1 })
+            .skip(offset)
+            .limit(i64::from(limit) + 1)
+            .build();
+        let filter = bson::doc! {
+            "$and": [
+                { "$text": { "$search": &query.q } },
+                Post::visible_to(auth.and_then(|auth| auth.id)),
+            ]
+        };
+
+        let documents = state.posts_collection.clone_with_type::<Document>();
+        let mut cursor = documents.find(filter, options).await?;
+
+        let mut items: Vec<SearchHit> = vec![];
+        while let Some(document) = cursor.next().await {
+            let document = document?;
+            let score = document.get_f64("score").unwrap_or_default();
+            let Ok(post) = bson::from_document::<Post>(document) else { continue };
+            let snippet = snippet(&post.content, &terms);
+            items.push(SearchHit {
+                post: PostView::from(post),
+                score,
+                snippet,
+            });
+        }
+
+        let next = if items.len() > limit as usize {
+            items.truncate(limit as usize);
+            Some(offset + u64::from(limit))
+        } else {
+            None
+        };
+
+        Ok((StatusCode::OK, Json(SearchResults { items, next })))
+    }
+}
+
+/// Lowercased words of a `$text` search string, leaving out negated terms.
+fn search_terms(q: &str) -> Vec<String> {
+    q.split_whitespace()
+        .filter(|term| !term.starts_with('-'))
+        .flat_map(|term| term.split(|character: char| !character.is_alphanumeric()))
+        .filter(|term| !term.is_empty())
+        .map(|term| term.to_lowercase())
+        .collect()
+}
+
+/// Byte ranges of the alphanumeric words in `text`.
+fn words(text: &str) -> Vec<(usize, usize)> {
+    let mut words = vec![];
+    let mut start = None;
+    for (index, character) in text.char_indices() {
+        match (character.is_alphanumeric(), start) {
+            (true, None) => start = Some(index),
+            (false, Some(word_start)) => {
+                words.push((word_start, index));
+                start = None;
+            }
+            _ => (),
+        }
+    }
+    if let Some(word_start) = start {
+        words.push((word_start, text.len()));
+    }
+    words
+}
+
+/// Moves `index` back to the closest char boundary of `text`.
+fn char_boundary(text: &str, mut index: usize) -> usize {
+    index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+fn snippet(content: &str, terms: &[String]) -> String {
+    // prefix matching is a rough stand-in for the stemming MongoDB applies
+    let is_match = |word: &str| {
+        let word = word.to_lowercase();
+        terms.iter().any(|term| word.starts_with(term.as_str()))
+    };
+    let words = words(content);
+    let first_match = words
+        .iter()
+        .find(|(start, end)| is_match(&content[*start..*end]))
+        .map(|(start, _)| *start)
+        .unwrap_or(0);
+
+    let start = char_boundary(content, first_match.saturating_sub(SNIPPET_LEAD));
+    let end = char_boundary(content, start + SNIPPET_LENGTH);
+
+    let mut snippet = String::new();
+    if start > 0 {
+        snippet.push('…');
+    }
+    let mut cursor = start;
+    for (word_start, word_end) in words {
+        if word_start < start || word_end > end {
+            continue;
+        }
+        let word = &content[word_start..word_end];
+        if is_match(word) {
+            snippet.push_str(&escape(&content[cursor..word_start]));
+            snippet.push_str("<mark>");
+            snippet.push_str(&escape(word));
+            snippet.push_str("</mark>");
+            cursor = word_end;
+        }
+    }
+    snippet.push_str(&escape(&content[cursor..end]));
+    if end < content.len() {
+        snippet.push('…');
+    }
+    snippet
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn terms(q: &str) -> Vec<String> {
+        search_terms(q)
+    }
+
+    #[test]
+    fn search_terms_split_on_punctuation_and_skip_negated_terms() {
+        assert_eq!(terms("Rust, async-await -java"), vec!["rust", "async", "await"]);
+    }
+
+    #[test]
+    fn search_terms_are_empty_for_punctuation_only() {
+        assert!(terms("  -- !! ").is_empty());
+    }
+
+    #[test]
+    fn search_terms_lowercase_multi_byte_words() {
+        assert_eq!(terms("Über Café"), vec!["über", "café"]);
+    }
+
+    #[test]
+    fn snippet_marks_every_matching_word() {
+        let snippet = snippet("Rust is fast and rustaceans love Rust.", &terms("rust"));
+
+        assert_eq!(snippet, "<mark>Rust</mark> is fast and <mark>rustaceans</mark> love <mark>Rust</mark>.");
+    }
+
+    #[test]
+    fn snippet_escapes_html_in_the_content() {
+        let snippet = snippet("<b>rust</b> & \"friends\"", &terms("rust"));
+
+        assert_eq!(snippet, "&lt;b&gt;<mark>rust</mark>&lt;/b&gt; &amp; &quot;friends&quot;");
+    }
+
+    #[test]
+    fn snippet_starts_near_the_first_match() {
+        let content = format!("{}needle and more text", "word ".repeat(40));
+        let snippet = snippet(&content, &terms("needle"));
+
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.contains("<mark>needle</mark>"));
+        assert!(!snippet.ends_with('…'));
+    }
+
+    #[test]
+    fn snippet_cuts_long_content_with_an_ellipsis() {
+        let content = "word ".repeat(100);
+        let snippet = snippet(&content, &terms("missing"));
+
+        assert!(!snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(!snippet.contains("<mark>"));
+    }
+
+    #[test]
+    fn snippet_keeps_multi_byte_text_at_the_boundaries_intact() {
+        // every character is two bytes, so the byte offsets land mid character
+        let content = format!("{}ü match {}", "é".repeat(SNIPPET_LEAD + 1), "ö".repeat(SNIPPET_LENGTH));
+        let snippet = snippet(&content, &terms("match"));
+
+        assert!(snippet.starts_with('…'));
+        assert!(snippet.ends_with('…'));
+        assert!(snippet.contains("<mark>match</mark>"));
+    }
+
+    #[test]
+    fn char_boundary_moves_back_inside_a_multi_byte_character() {
+        assert_eq!(char_boundary("aé", 2), 1);
+        assert_eq!(char_boundary("aé", 3), 3);
+        assert_eq!(char_boundary("aé", 10), 3);
+    }
+}
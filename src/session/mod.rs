@@ -1,9 +1,38 @@
-use axum::headers::Cookie;
-use chrono::{DateTime, Utc};
-use mongodb::bson::{self, oid::ObjectId};
+use async_trait::async_trait;
+use axum::extract::{ConnectInfo, FromRequestParts, Path, State};
+use axum::headers::authorization::Bearer;
+use axum::headers::{Authorization, Cookie};
+use axum::http::header::USER_AGENT;
+use axum::http::request::Parts;
+use axum::http::StatusCode;
+use axum::TypedHeader;
+use chrono::{DateTime, Duration, Utc};
+use mongodb::bson::{self, oid::ObjectId, Document};
+use mongodb::options::{FindOptions, IndexOptions};
+use mongodb::{Collection, IndexModel};
+use nanoid::nanoid;
 use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
 
-use crate::{auth::Auth, user::User, AppState};
+// needed to call .next() in mongodb Cursor type
+use futures::StreamExt;
+
+use crate::api_token::{ApiToken, Scope};
+use crate::error::{AppError, AppResult};
+use crate::view::{Json, View};
+use crate::user::{Permission, User};
+use crate::{auth::Auth, AppState};
+
+/// How stale `last_seen_at` may get before a request refreshes it, so that not every
+/// request costs a write.
+const LAST_SEEN_RESOLUTION: i64 = 5;
+/// Days a session stays valid without being used. Every use pushes `valid_until`
+/// forward by this much again...
+const IDLE_TIMEOUT: i64 = 7;
+/// ...but never past this many days after signing in.
+const MAX_LIFETIME: i64 = 30;
+/// Minutes a session waiting for its second factor has to receive it.
+const TWO_FACTOR_TIMEOUT: i64 = 5;
 
 #[derive(Serialize, Deserialize)]
 pub(crate) struct Session {
@@ -13,48 +42,647 @@ pub(crate) struct Session {
     pub user_id: Option<ObjectId>,
     pub session_id: String,
     pub valid_until: DateTime<Utc>,
+    /// `valid_until` as a BSON date, which the TTL index purging expired sessions needs.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub expires_at: Option<bson::DateTime>,
+    #[serde(default)]
+    pub user_agent: Option<String>,
+    #[serde(default)]
+    pub ip: Option<String>,
+    #[serde(default)]
+    pub last_seen_at: Option<DateTime<Utc>>,
+    /// Signed in with the password, still waiting for the TOTP code. Such a session
+    /// is ignored by `Session::current`, it is only good for `Auth::verify_two_factor`.
+    #[serde(default)]
+    pub two_factor_pending: bool,
     #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime<Utc>>,
     #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+/// A session as shown to its owner. The `session_id` only ever travels in the cookie.
+#[derive(Serialize, Debug)]
+pub(crate) struct SessionView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+    pub last_seen_at: Option<DateTime<Utc>>,
+    pub valid_until: DateTime<Utc>,
+    /// Whether this is the session the request was made with.
+    pub current: bool,
+    pub two_factor_pending: bool,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+impl View for SessionView {}
+
+/// The device a request comes from, recorded on the sessions it starts.
+pub(crate) struct ClientInfo {
+    pub user_agent: Option<String>,
+    pub ip: Option<String>,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for ClientInfo
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+        let user_agent = parts
+            .headers
+            .get(USER_AGENT)
+            .and_then(|value| value.to_str().ok())
+            .map(String::from);
+        let ip = parts
+            .extensions
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(address)| address.ip().to_string());
+
+        Ok(ClientInfo { user_agent, ip })
+    }
+}
+
+/// Who a request claims to be: a personal access token sent as `Authorization: Bearer`,
+/// or else the session cookie. Checked by `Session::auth` and friends.
+pub(crate) enum Credentials {
+    Token(String),
+    Session(Cookie),
+    None,
+}
+
+#[async_trait]
+impl<S> FromRequestParts<S> for Credentials
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        if let Ok(TypedHeader(Authorization(bearer))) =
+            TypedHeader::<Authorization<Bearer>>::from_request_parts(parts, state).await
+        {
+            return Ok(Credentials::Token(bearer.token().to_string()));
+        }
+
+        match TypedHeader::<Cookie>::from_request_parts(parts, state).await {
+            Ok(TypedHeader(cookie)) => Ok(Credentials::Session(cookie)),
+            Err(_) => Ok(Credentials::None),
+        }
+    }
+}
+
+impl Credentials {
+    /// The session the request was made with, `None` for tokens.
+    pub(crate) async fn session(&self, state: &AppState) -> AppResult<Option<Session>> {
+        match self {
+            Credentials::Session(cookie) => Session::current(cookie, state).await,
+            Credentials::Token(_) | Credentials::None => Ok(None),
+        }
+    }
+}
+
 impl Session {
-    pub(crate) async fn user(cookie: &Cookie, state: &AppState) -> Result<Option<User>, ()> {
-        let Some(session_id) = cookie.get("session_id") else { return Ok(None) };
-        let session_query = state
-            .sessions_collection
-            .find_one(bson::doc! { "session_id": session_id }, None)
-            .await;
-        let Ok(session) = session_query else { return Err(()) };
-        let Some(session) = session else { return Ok(None) };
+    pub(crate) async fn create_indexes(
+        sessions_collection: &Collection<Session>,
+    ) -> mongodb::error::Result<()> {
+        let session_id_index = IndexModel::builder()
+            .keys(bson::doc! { "session_id": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        let auth_index = IndexModel::builder()
+            .keys(bson::doc! { "auth_id": 1 })
+            .build();
+        let ttl_index = IndexModel::builder()
+            .keys(bson::doc! { "expires_at": 1 })
+            .options(
+                IndexOptions::builder()
+                    .expire_after(std::time::Duration::ZERO)
+                    .build(),
+            )
+            .build();
+        sessions_collection
+            .create_indexes([session_id_index, auth_index, ttl_index], None)
+            .await?;
 
-        let user_query = state
+        Ok(())
+    }
+
+    /// Signs `auth_id` in on the device described by `client`, next to any sessions
+    /// it already has elsewhere. With `two_factor_pending` the session only lives for
+    /// `TWO_FACTOR_TIMEOUT` unless the TOTP code follows.
+    pub(crate) async fn start(
+        state: &AppState,
+        auth_id: ObjectId,
+        client: ClientInfo,
+        two_factor_pending: bool,
+    ) -> AppResult<Session> {
+        let user = state
             .users_collection
-            .find_one(bson::doc! { "_id": session.user_id }, None)
-            .await;
-        let Ok(user) = user_query else { return Err(()) };
-        let Some(user) = user else { return Ok(None) };
+            .find_one(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+
+        let now = Utc::now();
+        let valid_until = match two_factor_pending {
+            true => now + Duration::minutes(TWO_FACTOR_TIMEOUT),
+            false => now + Duration::days(IDLE_TIMEOUT),
+        };
+        let mut session = Session {
+            id: None,
+            auth_id,
+            user_id: user.and_then(|user| user.id),
+            session_id: nanoid!(),
+            valid_until,
+            expires_at: Some(expiry_date(valid_until)),
+            user_agent: client.user_agent,
+            ip: client.ip,
+            last_seen_at: Some(now),
+            two_factor_pending,
+            created_at: Some(now),
+            updated_at: Some(now),
+        };
+        let document = state.sessions_collection.insert_one(&session, None).await?;
+        session.id = document.inserted_id.as_object_id();
+
+        Ok(session)
+    }
+
+    /// When the session expires if it is used at `now`: `IDLE_TIMEOUT` later, capped at
+    /// `MAX_LIFETIME` after it started.
+    fn extended_until(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+        let started_at = self.created_at.unwrap_or(now);
+        (now + Duration::days(IDLE_TIMEOUT)).min(started_at + Duration::days(MAX_LIFETIME))
+    }
+
+    fn is_expired(&self, now: DateTime<Utc>) -> bool {
+        self.valid_until <= now
+    }
+
+    /// Whether `last_seen_at` is older than `LAST_SEEN_RESOLUTION` and due for a refresh.
+    fn is_stale(&self, now: DateTime<Utc>) -> bool {
+        match self.last_seen_at {
+            Some(last_seen_at) => now - last_seen_at > Duration::minutes(LAST_SEEN_RESOLUTION),
+            None => true,
+        }
+    }
+
+    /// Swaps the `session_id` for a fresh one, keeping everything else. Meant for
+    /// whenever the session gains privileges, so an id that leaked before stays useless.
+    pub(crate) async fn rotate(&mut self, state: &AppState) -> AppResult<()> {
+        let update = self.renew_id(Utc::now())?;
+        state
+            .sessions_collection
+            .update_one(bson::doc! { "_id": self.id }, update, None)
+            .await?;
 
-        Ok(Some(user))
+        Ok(())
     }
 
-    pub(crate) async fn auth(cookie: &Cookie, state: &AppState) -> Result<Option<Auth>, ()> {
+    /// Gives the session a fresh `session_id`, returning the update that stores it.
+    fn renew_id(&mut self, now: DateTime<Utc>) -> bson::ser::Result<Document> {
+        self.session_id = nanoid!();
+        Ok(bson::doc! { "$set": {
+            "session_id": &self.session_id,
+            "updatedAt": bson::to_bson(&now)?,
+        } })
+    }
+
+    /// Turns a session waiting for its second factor into a full one, under a new
+    /// `session_id` since it just gained privileges.
+    pub(crate) async fn complete_two_factor(&mut self, state: &AppState) -> AppResult<()> {
+        let now = Utc::now();
+        let valid_until = now + Duration::days(IDLE_TIMEOUT);
+        let expires_at = expiry_date(valid_until);
+        let session_id = nanoid!();
+        state
+            .sessions_collection
+            .update_one(
+                bson::doc! { "_id": self.id },
+                bson::doc! { "$set": {
+                    "session_id": &session_id,
+                    "two_factor_pending": false,
+                    "valid_until": bson::to_bson(&valid_until)?,
+                    "expires_at": expires_at,
+                    "updatedAt": bson::to_bson(&now)?,
+                } },
+                None,
+            )
+            .await?;
+        self.session_id = session_id;
+        self.two_factor_pending = false;
+        self.valid_until = valid_until;
+        self.expires_at = Some(expires_at);
+
+        Ok(())
+    }
+
+    /// Ends every session of `auth_id` except `keep`.
+    pub(crate) async fn revoke_others(
+        state: &AppState,
+        auth_id: ObjectId,
+        keep: Option<ObjectId>,
+    ) -> AppResult<()> {
+        state
+            .sessions_collection
+            .delete_many(Session::others_of(auth_id, keep), None)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Matches the sessions of `auth_id` other than `keep`, or all of them for `None`.
+    fn others_of(auth_id: ObjectId, keep: Option<ObjectId>) -> Document {
+        bson::doc! { "auth_id": auth_id, "_id": { "$ne": keep } }
+    }
+
+    pub(crate) fn into_view(self, current: bool) -> SessionView {
+        SessionView {
+            id: self.id,
+            user_agent: self.user_agent,
+            ip: self.ip,
+            last_seen_at: self.last_seen_at,
+            valid_until: self.valid_until,
+            current,
+            two_factor_pending: self.two_factor_pending,
+            created_at: self.created_at,
+        }
+    }
+
+    /// Loads the fully signed in session `cookie` refers to, unless it expired. Signing
+    /// out deletes the document, so a revoked `session_id` stops matching right away.
+    /// Using a session slides its expiry forward, see `IDLE_TIMEOUT`.
+    pub(crate) async fn current(cookie: &Cookie, state: &AppState) -> AppResult<Option<Session>> {
+        let session = Session::load(cookie, state).await?;
+        Ok(session.filter(|session| !session.two_factor_pending))
+    }
+
+    /// Loads the session `cookie` refers to if it still waits for its second factor.
+    pub(crate) async fn pending(cookie: &Cookie, state: &AppState) -> AppResult<Option<Session>> {
+        let session = Session::load(cookie, state).await?;
+        Ok(session.filter(|session| session.two_factor_pending))
+    }
+
+    async fn load(cookie: &Cookie, state: &AppState) -> AppResult<Option<Session>> {
         let Some(session_id) = cookie.get("session_id") else { return Ok(None) };
-        let session_query = state
+        let session = state
             .sessions_collection
             .find_one(bson::doc! { "session_id": session_id }, None)
-            .await;
-        let Ok(session) = session_query else { return Err(()) };
+            .await?;
+        let Some(mut session) = session else { return Ok(None) };
+
+        // the TTL monitor only runs once a minute, and older sessions have no expires_at
+        let now = Utc::now();
+        if session.is_expired(now) {
+            return Ok(None);
+        }
+
+        if session.is_stale(now) && !session.two_factor_pending {
+            let valid_until = session.extended_until(now);
+            let expires_at = expiry_date(valid_until);
+            state
+                .sessions_collection
+                .update_one(
+                    bson::doc! { "_id": session.id },
+                    bson::doc! { "$set": {
+                        "last_seen_at": bson::to_bson(&now)?,
+                        "valid_until": bson::to_bson(&valid_until)?,
+                        "expires_at": expires_at,
+                    } },
+                    None,
+                )
+                .await?;
+            session.last_seen_at = Some(now);
+            session.valid_until = valid_until;
+            session.expires_at = Some(expires_at);
+        }
+
+        Ok(Some(session))
+    }
+
+    pub(crate) async fn user(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<Option<User>> {
+        let filter = match credentials {
+            Credentials::Token(token) => {
+                let auth = ApiToken::authenticate(state, token, scope).await?;
+                bson::doc! { "auth_id": auth.id }
+            }
+            Credentials::Session(cookie) => {
+                let Some(session) = Session::current(cookie, state).await? else { return Ok(None) };
+                bson::doc! { "_id": session.user_id }
+            }
+            Credentials::None => return Ok(None),
+        };
+
+        let user = state.users_collection.find_one(filter, None).await?;
+
+        Ok(user)
+    }
+
+    /// The account behind `credentials`. A token also has to be granted `scope`, a
+    /// signed in session may do anything its account may.
+    pub(crate) async fn auth(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<Option<Auth>> {
+        let session = match credentials {
+            Credentials::Token(token) => return ApiToken::authenticate(state, token, scope).await.map(Some),
+            Credentials::Session(cookie) => Session::current(cookie, state).await?,
+            Credentials::None => None,
+        };
         let Some(session) = session else { return Ok(None) };
 
-        let auth_query = state
+        let auth = state
             .auths_collection
             .find_one(bson::doc! { "_id": session.auth_id }, None)
-            .await;
-        let Ok(auth) = auth_query else { return Err(()) };
-        let Some(auth) = auth else { return Ok(None) };
+            .await?;
+
+        Ok(auth)
+    }
+
+    /// Like `Session::user`, failing with `AppError::Unauthorized` when signed out.
+    pub(crate) async fn require_user(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<User> {
+        let Some(user) = Session::user(credentials, state, scope).await? else { return Err(AppError::Unauthorized) };
+        Ok(user)
+    }
+
+    /// Like `Session::auth`, failing with `AppError::Unauthorized` when signed out.
+    pub(crate) async fn require_auth(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<Auth> {
+        let Some(auth) = Session::auth(credentials, state, scope).await? else { return Err(AppError::Unauthorized) };
+        Ok(auth)
+    }
+
+    /// Both the user and the account behind `credentials`, looking the session up only
+    /// once. Fails with `AppError::Unauthorized` when signed out and `AppError::Forbidden`
+    /// for accounts without a user.
+    pub(crate) async fn require_account(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+    ) -> AppResult<(User, Auth)> {
+        let auth = Session::require_auth(credentials, state, scope).await?;
+        let user = state
+            .users_collection
+            .find_one(bson::doc! { "auth_id": auth.id }, None)
+            .await?;
+        let Some(user) = user else { return Err(AppError::Forbidden) };
+
+        Ok((user, auth))
+    }
+
+    /// Like `Session::require_account`, if the user's role also grants `permission`.
+    pub(crate) async fn authorize_account(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+        permission: Permission,
+    ) -> AppResult<(User, Auth)> {
+        let (user, auth) = Session::require_account(credentials, state, scope).await?;
+        if !user.owns_or_can(false, permission) {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok((user, auth))
+    }
+
+    /// The user behind `credentials` if their role grants `permission`. Fails with
+    /// `AppError::Unauthorized` when signed out and `AppError::Forbidden` otherwise,
+    /// also for accounts without a user, which have no role.
+    pub(crate) async fn authorize(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+        permission: Permission,
+    ) -> AppResult<User> {
+        Session::authorize_owner_or(credentials, state, scope, |_| false, permission).await
+    }
+
+    /// The user behind `credentials` if `owner` says they own what is at stake, or
+    /// else if their role grants `permission`. Fails like `Session::authorize`.
+    pub(crate) async fn authorize_owner_or(
+        credentials: &Credentials,
+        state: &AppState,
+        scope: Scope,
+        owner: impl FnOnce(&User) -> bool,
+        permission: Permission,
+    ) -> AppResult<User> {
+        let Some(user) = Session::user(credentials, state, scope).await? else {
+            return match Session::auth(credentials, state, scope).await? {
+                Some(_) => Err(AppError::Forbidden),
+                None => Err(AppError::Unauthorized),
+            };
+        };
+        if !user.owns_or_can(owner(&user), permission) {
+            return Err(AppError::Forbidden);
+        }
+
+        Ok(user)
+    }
+
+    /// Lists the sessions of the signed in account, most recently used first.
+    pub(crate) async fn read_all(
+        TypedHeader(cookie): TypedHeader<Cookie>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<Vec<SessionView>>)> {
+        let Some(current) = Session::current(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+
+        let options = FindOptions::builder()
+            .sort(bson::doc! { "last_seen_at": -1, "_id": -1 })
+            .build();
+        let mut cursor = state
+            .sessions_collection
+            .find(bson::doc! { "auth_id": current.auth_id }, options)
+            .await?;
+
+        let mut sessions: Vec<SessionView> = vec![];
+        while let Some(session) = cursor.next().await {
+            let session = session?;
+            let is_current = session.id == current.id;
+            sessions.push(session.into_view(is_current));
+        }
+
+        Ok((StatusCode::FOUND, Json(sessions)))
+    }
+
+    /// Revokes one of the signed in account's sessions, e.g. a lost device.
+    pub(crate) async fn delete(
+        TypedHeader(cookie): TypedHeader<Cookie>,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+    ) -> AppResult<StatusCode> {
+        let Some(current) = Session::current(&cookie, &state).await? else { return Err(AppError::Unauthorized) };
+
+        let result = state
+            .sessions_collection
+            .delete_one(bson::doc! { "_id": id, "auth_id": current.auth_id }, None)
+            .await?;
+        if result.deleted_count == 0 {
+            return Err(AppError::NotFound);
+        }
+
+        Ok(StatusCode::NO_CONTENT)
+    }
+}
+
+/// `valid_until` as the BSON date stored in `expires_at` for the TTL index.
+fn expiry_date(valid_until: DateTime<Utc>) -> bson::DateTime {
+    bson::DateTime::from_millis(valid_until.timestamp_millis())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use axum::http::Request;
+    use std::net::{IpAddr, Ipv4Addr};
+
+    fn session(now: DateTime<Utc>) -> Session {
+        Session {
+            id: Some(ObjectId::new()),
+            auth_id: ObjectId::new(),
+            user_id: Some(ObjectId::new()),
+            session_id: nanoid!(),
+            valid_until: now + Duration::days(IDLE_TIMEOUT),
+            expires_at: None,
+            user_agent: Some(String::from("Firefox")),
+            ip: Some(String::from("192.0.2.1")),
+            last_seen_at: Some(now),
+            two_factor_pending: false,
+            created_at: Some(now),
+            updated_at: Some(now),
+        }
+    }
+
+    async fn client_info(request: Request<()>) -> ClientInfo {
+        let (mut parts, _) = request.into_parts();
+        ClientInfo::from_request_parts(&mut parts, &()).await.unwrap()
+    }
+
+    #[test]
+    fn signing_out_elsewhere_keeps_the_current_session() {
+        let auth_id = ObjectId::new();
+        let current = ObjectId::new();
+
+        assert_eq!(
+            Session::others_of(auth_id, Some(current)),
+            bson::doc! { "auth_id": auth_id, "_id": { "$ne": current } }
+        );
+    }
+
+    #[test]
+    fn signing_out_everywhere_revokes_every_session_of_the_account() {
+        let auth_id = ObjectId::new();
+
+        // every stored session has an `_id`, so `$ne: null` spares none of them
+        assert_eq!(
+            Session::others_of(auth_id, None),
+            bson::doc! { "auth_id": auth_id, "_id": { "$ne": null } }
+        );
+    }
+
+    #[tokio::test]
+    async fn client_info_records_the_user_agent_and_ip() {
+        let address = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 0, 2, 7)), 4000);
+        let request = Request::builder()
+            .header(USER_AGENT, "Mozilla/5.0")
+            .extension(ConnectInfo(address))
+            .body(())
+            .unwrap();
+        let client = client_info(request).await;
+
+        assert_eq!(client.user_agent.as_deref(), Some("Mozilla/5.0"));
+        assert_eq!(client.ip.as_deref(), Some("192.0.2.7"));
+    }
+
+    #[tokio::test]
+    async fn client_info_is_empty_without_headers_or_address() {
+        let client = client_info(Request::new(())).await;
+
+        assert_eq!(client.user_agent, None);
+        assert_eq!(client.ip, None);
+    }
+
+    #[test]
+    fn listed_sessions_show_the_device_but_not_the_session_id() {
+        let session = session(Utc::now());
+        let session_id = session.session_id.clone();
+        let view = serde_json::to_value(session.into_view(true)).unwrap();
+
+        assert_eq!(view["user_agent"], "Firefox");
+        assert_eq!(view["ip"], "192.0.2.1");
+        assert_eq!(view["current"], true);
+        assert!(view.get("session_id").is_none());
+        assert!(!view.to_string().contains(&session_id));
+    }
+
+    #[test]
+    fn last_seen_is_refreshed_once_it_gets_stale() {
+        let now = Utc::now();
+        let mut session = session(now);
+
+        assert!(!session.is_stale(now + Duration::minutes(LAST_SEEN_RESOLUTION)));
+        assert!(session.is_stale(now + Duration::minutes(LAST_SEEN_RESOLUTION + 1)));
+        session.last_seen_at = None;
+        assert!(session.is_stale(now));
+    }
+
+    #[test]
+    fn using_a_session_slides_its_expiry() {
+        let now = Utc::now();
+        let session = session(now);
+        let later = now + Duration::days(3);
+
+        assert_eq!(session.extended_until(later), later + Duration::days(IDLE_TIMEOUT));
+    }
+
+    #[test]
+    fn sliding_never_passes_the_absolute_lifetime() {
+        let now = Utc::now();
+        let session = session(now);
+        let near_the_end = now + Duration::days(MAX_LIFETIME - 1);
+
+        assert_eq!(session.extended_until(near_the_end), now + Duration::days(MAX_LIFETIME));
+    }
+
+    #[test]
+    fn sessions_expire_at_valid_until() {
+        let now = Utc::now();
+        let session = session(now);
+
+        assert!(!session.is_expired(session.valid_until - Duration::seconds(1)));
+        assert!(session.is_expired(session.valid_until));
+    }
+
+    #[test]
+    fn the_ttl_date_matches_valid_until() {
+        let valid_until = Utc::now() + Duration::days(IDLE_TIMEOUT);
+
+        assert_eq!(expiry_date(valid_until).timestamp_millis(), valid_until.timestamp_millis());
+    }
+
+    #[test]
+    fn rotating_replaces_the_session_id() {
+        let now = Utc::now();
+        let mut session = session(now);
+        let previous = session.session_id.clone();
+
+        let update = session.renew_id(now).unwrap();
 
-        Ok(Some(auth))
+        assert_ne!(session.session_id, previous);
+        assert_eq!(update.get_document("$set").unwrap().get_str("session_id").unwrap(), session.session_id);
     }
 }
@@ -0,0 +1,297 @@
+use crate::api_token::Scope;
+use crate::error::{AppError, AppResult, FieldError};
+use crate::post::Post;
+use crate::session::{Credentials, Session};
+use crate::user::Permission;
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::utils::slug::slugify;
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Path, State};
+use axum::http::{HeaderMap, StatusCode};
+use chrono::{DateTime, Utc};
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{IndexOptions, UpdateOptions};
+use mongodb::{Collection, IndexModel};
+use serde::{Deserialize, Serialize};
+
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub(crate) struct Tag {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    /// Number of posts carrying this tag.
+    pub count: i64,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct RenameTag {
+    pub name: String,
+}
+
+#[derive(Deserialize)]
+pub(crate) struct MergeTag {
+    pub into: String,
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct TagView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub name: String,
+    pub count: i64,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
+
+impl View for TagView {}
+
+impl From<Tag> for TagView {
+    fn from(tag: Tag) -> Self {
+        TagView {
+            id: tag.id,
+            name: tag.name,
+            count: tag.count,
+            created_at: tag.created_at,
+            updated_at: tag.updated_at,
+        }
+    }
+}
+
+impl Tag {
+    /// Normalizes tag names the same way as slugs and drops empty names and duplicates.
+    pub(crate) fn normalize(tags: &[String]) -> Vec<String> {
+        let mut normalized: Vec<String> = vec![];
+        for tag in tags {
+            let tag = Tag::normalize_name(tag);
+            if !tag.is_empty() && !normalized.contains(&tag) {
+                normalized.push(tag);
+            }
+        }
+        normalized
+    }
+
+    /// Normalizes a single tag name, as used in paths and rename/merge bodies.
+    fn normalize_name(name: &str) -> String {
+        slugify(name)
+    }
+
+    /// Normalizes the tag a rename or merge starts `from` and the one it goes `into`,
+    /// rejecting an empty target and a tag folded into itself.
+    fn retag_names(from: &str, into: &str) -> AppResult<(String, String)> {
+        let from = Tag::normalize_name(from);
+        let into = Tag::normalize_name(into);
+        if into.is_empty() {
+            return Err(AppError::Validation(vec![FieldError::new(
+                "name",
+                "must contain at least one letter or digit",
+            )]));
+        }
+        if from == into {
+            return Err(AppError::BadRequest(String::from("A tag cannot be renamed or merged into itself.")));
+        }
+
+        Ok((from, into))
+    }
+
+    pub(crate) async fn create_indexes(
+        tags_collection: &Collection<Tag>,
+    ) -> mongodb::error::Result<()> {
+        let name_index = IndexModel::builder()
+            .keys(bson::doc! { "name": 1 })
+            .options(IndexOptions::builder().unique(true).build())
+            .build();
+        tags_collection.create_index(name_index, None).await?;
+
+        Ok(())
+    }
+
+    /// Sets the count of every tag in `tags` to its number of published posts, creating
+    /// missing tags and removing the ones no published post uses. Drafts, scheduled and
+    /// archived posts are left out so their tags don't show up in `GET /api/tags`.
+    pub(crate) async fn recount(
+        posts_collection: &Collection<Post>,
+        tags_collection: &Collection<Tag>,
+        tags: &[String],
+    ) -> mongodb::error::Result<()> {
+        let now = bson::to_bson(&Utc::now())?;
+        for tag in tags {
+            let count = posts_collection
+                .count_documents(bson::doc! { "$and": [{ "tags": tag }, Post::visible_to(None)] }, None)
+                .await?;
+            if count == 0 {
+                tags_collection
+                    .delete_one(bson::doc! { "name": tag }, None)
+                    .await?;
+                continue;
+            }
+            tags_collection
+                .update_one(
+                    bson::doc! { "name": tag },
+                    bson::doc! {
+                        "$set": { "count": count as i64, "updatedAt": &now },
+                        "$setOnInsert": { "createdAt": &now },
+                    },
+                    UpdateOptions::builder().upsert(true).build(),
+                )
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Recounts every tag in use by a post or already stored.
+    pub(crate) async fn recount_all(
+        posts_collection: &Collection<Post>,
+        tags_collection: &Collection<Tag>,
+    ) -> mongodb::error::Result<()> {
+        let mut tags = posts_collection.distinct("tags", None, None).await?;
+        tags.extend(tags_collection.distinct("name", None, None).await?);
+        let mut tags: Vec<String> = tags.into_iter().filter_map(|tag| tag.as_str().map(String::from)).collect();
+        tags.sort();
+        tags.dedup();
+
+        Tag::recount(posts_collection, tags_collection, &tags).await
+    }
+
+    /// Moves every post tagged `from` over to `into`, then recounts both tags.
+    async fn retag(state: &AppState, from: &str, into: &str) -> mongodb::error::Result<Option<Tag>> {
+        state
+            .posts_collection
+            .update_many(
+                bson::doc! { "tags": from },
+                bson::doc! { "$addToSet": { "tags": into } },
+                None,
+            )
+            .await?;
+        state
+            .posts_collection
+            .update_many(
+                bson::doc! { "tags": from },
+                bson::doc! { "$pull": { "tags": from } },
+                None,
+            )
+            .await?;
+
+        let tags = [from.to_string(), into.to_string()];
+        Tag::recount(&state.posts_collection, &state.tags_collection, &tags).await?;
+
+        state
+            .tags_collection
+            .find_one(bson::doc! { "name": into }, None)
+            .await
+    }
+
+    pub(crate) async fn read_all(
+        _: Credentials,
+        params: ListParams<NoFilter>,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<TagView>>)> {
+        let page = params.find_page(&state.tags_collection, bson::doc! {}).await?;
+
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(TagView::from))))
+    }
+
+    async fn find(state: &AppState, name: &str) -> AppResult<Option<Tag>> {
+        let tag = state
+            .tags_collection
+            .find_one(bson::doc! { "name": name }, None)
+            .await?;
+
+        Ok(tag)
+    }
+
+    pub(crate) async fn rename(
+        credentials: Credentials,
+        Path(name): Path<String>,
+        State(state): State<AppState>,
+        Json(json): Json<RenameTag>,
+    ) -> AppResult<(StatusCode, Json<TagView>)> {
+        Session::authorize(&credentials, &state, Scope::TagsWrite, Permission::TagsManage).await?;
+
+        let (name, new_name) = Tag::retag_names(&name, &json.name)?;
+        if Tag::find(&state, &new_name).await?.is_some() {
+            return Err(AppError::Conflict(format!("Tag {new_name} already exists, merge into it instead.")));
+        }
+        if Tag::find(&state, &name).await?.is_none() {
+            return Err(AppError::NotFound);
+        }
+
+        let Some(tag) = Tag::retag(&state, &name, &new_name).await? else { return Err(AppError::NotFound) };
+
+        Ok((StatusCode::OK, Json(TagView::from(tag))))
+    }
+
+    pub(crate) async fn merge(
+        credentials: Credentials,
+        Path(name): Path<String>,
+        State(state): State<AppState>,
+        Json(json): Json<MergeTag>,
+    ) -> AppResult<(StatusCode, Json<TagView>)> {
+        Session::authorize(&credentials, &state, Scope::TagsWrite, Permission::TagsManage).await?;
+
+        let (name, into) = Tag::retag_names(&name, &json.into)?;
+        for tag_name in [&name, &into] {
+            if Tag::find(&state, tag_name).await?.is_none() {
+                return Err(AppError::NotFound);
+            }
+        }
+
+        let Some(tag) = Tag::retag(&state, &name, &into).await? else { return Err(AppError::NotFound) };
+
+        Ok((StatusCode::OK, Json(TagView::from(tag))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn names(tags: &[&str]) -> Vec<String> {
+        tags.iter().map(|tag| tag.to_string()).collect()
+    }
+
+    #[test]
+    fn normalize_slugifies_and_drops_empty_and_duplicate_tags() {
+        let tags = Tag::normalize(&names(&["Rust Lang", "rust-lang", "  ", "!!", "Axum"]));
+
+        assert_eq!(tags, names(&["rust-lang", "axum"]));
+    }
+
+    #[test]
+    fn normalize_keeps_order_of_first_occurrence() {
+        let tags = Tag::normalize(&names(&["b", "a", "B"]));
+
+        assert_eq!(tags, names(&["b", "a"]));
+    }
+
+    #[test]
+    fn retag_names_normalizes_the_path_and_the_target() {
+        let (from, into) = Tag::retag_names("Rust Lang", "Rust!").unwrap();
+
+        assert_eq!(from, "rust-lang");
+        assert_eq!(into, "rust");
+    }
+
+    #[test]
+    fn retag_names_rejects_an_empty_target() {
+        let result = Tag::retag_names("rust", " !! ");
+
+        assert!(matches!(result, Err(AppError::Validation(_))));
+    }
+
+    #[test]
+    fn retag_names_rejects_renaming_a_tag_into_itself() {
+        let result = Tag::retag_names("Rust", "rust");
+
+        assert!(matches!(result, Err(AppError::BadRequest(_))));
+    }
+}
@@ -1,186 +1,223 @@
-use crate::session::Session;
+mod role;
+
+use crate::api_token::Scope;
+use crate::error::{stored_id, AppError, AppResult};
+use crate::session::{Credentials, Session};
 use crate::utils::database::Crud;
+use crate::utils::query::{ListParams, NoFilter, Page};
+use crate::view::{Json, View};
 use crate::AppState;
 use async_trait::async_trait;
 use axum::extract::{Path, State};
-use axum::headers::Cookie;
-use axum::http::StatusCode;
-use axum::{Json, TypedHeader};
+use axum::http::{HeaderMap, StatusCode};
 use chrono::{DateTime, Utc};
 use mongodb::bson;
 use mongodb::bson::oid::ObjectId;
 use serde::{Deserialize, Serialize};
+pub(crate) use role::{Permission, Role};
 
-// needed to call .next() in mongodb Cursor type
-use futures::StreamExt;
+#[derive(Deserialize, Serialize, Debug, PartialEq)]
+pub(crate) struct User {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub auth_id: ObjectId,
+    pub display_name: String,
+    pub role: Role,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
+    #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<DateTime<Utc>>,
+}
 
-#[derive(Deserialize, Serialize, PartialEq, Debug)]
-pub(crate) enum Role {
-    User,
-    Developer,
+/// What anyone may see of a user.
+#[derive(Serialize, Debug)]
+pub(crate) struct PublicUserView {
+    #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
+    pub id: Option<ObjectId>,
+    pub display_name: String,
+    #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<DateTime<Utc>>,
 }
 
-#[derive(Deserialize, Serialize, Debug, PartialEq)]
-pub(crate) struct User {
+/// What a user sees of themselves, and what those managing users see of everyone.
+#[derive(Serialize, Debug)]
+pub(crate) struct SelfUserView {
     #[serde(rename = "_id", skip_serializing_if = "Option::is_none")]
     pub id: Option<ObjectId>,
     pub auth_id: ObjectId,
     pub display_name: String,
     pub role: Role,
+    pub permissions: &'static [Permission],
     #[serde(rename = "createdAt", skip_serializing_if = "Option::is_none")]
     pub created_at: Option<DateTime<Utc>>,
     #[serde(rename = "updatedAt", skip_serializing_if = "Option::is_none")]
     pub updated_at: Option<DateTime<Utc>>,
 }
 
+#[derive(Serialize, Debug)]
+#[serde(untagged)]
+pub(crate) enum UserView {
+    Public(PublicUserView),
+    Own(SelfUserView),
+}
+
+impl View for PublicUserView {}
+impl View for SelfUserView {}
+impl View for UserView {}
+
+impl From<User> for PublicUserView {
+    fn from(user: User) -> Self {
+        PublicUserView {
+            id: user.id,
+            display_name: user.display_name,
+            created_at: user.created_at,
+        }
+    }
+}
+
+impl From<User> for SelfUserView {
+    fn from(user: User) -> Self {
+        SelfUserView {
+            id: user.id,
+            auth_id: user.auth_id,
+            display_name: user.display_name,
+            role: user.role,
+            permissions: user.role.permissions(),
+            created_at: user.created_at,
+            updated_at: user.updated_at,
+        }
+    }
+}
+
+impl UserView {
+    fn full(user: User) -> Self {
+        UserView::Own(SelfUserView::from(user))
+    }
+}
+
 #[async_trait]
 impl Crud<User, User> for User {
+    type Filter = NoFilter;
+    type View = UserView;
+
     async fn create(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         State(state): State<AppState>,
         Json(json): Json<User>,
-    ) -> (StatusCode, Json<Option<User>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        let auth_id = auth.id.expect("User has no id");
+    ) -> AppResult<(StatusCode, Json<UserView>)> {
+        let auth = Session::require_auth(&credentials, &state, Scope::UsersWrite).await?;
+        let auth_id = stored_id(auth.id)?;
 
-        let user_query = state
+        let user = state
             .users_collection
             .find_one(bson::doc! { "auth_id": auth_id }, None)
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let None = user else { return (StatusCode::FORBIDDEN, Json(None)) };
+            .await?;
+        if user.is_some() {
+            return Err(AppError::Conflict(String::from("This account already has a user.")));
+        }
 
         let now = Utc::now();
         let mut user = User {
             id: None,
             auth_id,
             display_name: json.display_name,
-            role: Role::User,
+            role: Role::default(),
             created_at: Some(now),
             updated_at: Some(now),
         };
 
-        let query = state.users_collection.insert_one(&user, None).await;
+        let document = state.users_collection.insert_one(&user, None).await?;
+        user.id = document.inserted_id.as_object_id();
 
-        match query {
-            Ok(document) => {
-                let id = document.inserted_id;
-                user.id = id.as_object_id();
-                (StatusCode::CREATED, Json(Some(user)))
-            }
-            Err(_) => (StatusCode::INTERNAL_SERVER_ERROR, Json(Some(user))),
-        }
+        Ok((StatusCode::CREATED, Json(UserView::full(user))))
     }
 
     async fn read_all(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
+        params: ListParams<NoFilter>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<Vec<User>>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        let user_query = state
-            .users_collection
-            .find_one(
-                bson::doc! { "auth_id": auth.id.expect("User has no id") },
-                None,
-            )
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::FORBIDDEN, Json(None)) };
-
-        if user.role != Role::Developer {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        };
-
-        let users_cursor_query = state.users_collection.find(None, None).await;
-        let Ok(mut users_cursor) = users_cursor_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
+    ) -> AppResult<(StatusCode, HeaderMap, Json<Page<UserView>>)> {
+        Session::authorize(&credentials, &state, Scope::UsersRead, Permission::UsersManage).await?;
 
-        let mut users: Vec<User> = vec![];
+        let page = params.find_page(&state.users_collection, bson::doc! {}).await?;
 
-        while let Some(user_result) = users_cursor.next().await {
-            if let Ok(user) = user_result {
-                users.push(user);
-            }
-        }
-
-        (StatusCode::FOUND, Json(Some(users)))
+        Ok((StatusCode::FOUND, params.link_headers(&page.next), Json(page.map(UserView::full))))
     }
 
+    /// Shows the full user to themselves and to those managing users, and the public
+    /// fields to everyone else.
     async fn read(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<User>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
+    ) -> AppResult<(StatusCode, Json<UserView>)> {
+        let viewer = Session::user(&credentials, &state, Scope::UsersRead).await?;
 
-        if auth.id.expect("User has no id") != id {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        };
-
-        let user_query = state
+        let user = state
             .users_collection
             .find_one(bson::doc! { "_id": id }, None)
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::FORBIDDEN, Json(None)) };
+            .await?;
+        let Some(user) = user else { return Err(AppError::NotFound) };
+
+        let view = match viewer {
+            Some(viewer) if viewer.owns_or_can(viewer.id == user.id, Permission::UsersManage) => {
+                UserView::full(user)
+            }
+            _ => UserView::Public(PublicUserView::from(user)),
+        };
 
-        (StatusCode::FOUND, Json(Some(user)))
+        Ok((StatusCode::FOUND, Json(view)))
     }
 
     async fn update(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
         Json(json): Json<User>,
-    ) -> (StatusCode, Json<Option<User>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        if auth.id.expect("User has no id") != id {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        };
-
-        let user_query = state.users_collection
+    ) -> AppResult<(StatusCode, Json<UserView>)> {
+        let user = Session::authorize_owner_or(
+            &credentials,
+            &state,
+            Scope::UsersWrite,
+            |user| user.id == Some(id),
+            Permission::UsersManage,
+        )
+        .await?;
+        User::ensure_manages(&state, &user, id).await?;
+
+        let user = state.users_collection
             .find_one_and_update(
                 bson::doc! { "_id": id },
                 bson::doc! { "$set": { "displayName": json.display_name, "updated_at": Utc::now().to_rfc3339() } },
                 None
             )
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::FORBIDDEN, Json(None)) };
+            .await?;
+        let Some(user) = user else { return Err(AppError::NotFound) };
 
-        (StatusCode::OK, Json(Some(user)))
+        Ok((StatusCode::OK, Json(UserView::full(user))))
     }
 
     async fn delete(
-        TypedHeader(cookie): TypedHeader<Cookie>,
+        credentials: Credentials,
         Path(id): Path<ObjectId>,
         State(state): State<AppState>,
-    ) -> (StatusCode, Json<Option<User>>) {
-        let auth = Session::auth(&cookie, &state).await;
-        let Ok(auth) = auth else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(auth) = auth else { return (StatusCode::UNAUTHORIZED, Json(None)) };
-
-        if auth.id.expect("User has no id") != id {
-            return (StatusCode::UNAUTHORIZED, Json(None));
-        };
-
-        let user_query = state
+    ) -> AppResult<(StatusCode, Json<UserView>)> {
+        let user = Session::authorize_owner_or(
+            &credentials,
+            &state,
+            Scope::UsersWrite,
+            |user| user.id == Some(id),
+            Permission::UsersManage,
+        )
+        .await?;
+        User::ensure_manages(&state, &user, id).await?;
+
+        let user = state
             .users_collection
             .find_one_and_delete(bson::doc! { "_id": id }, None)
-            .await;
-        let Ok(user) = user_query else { return (StatusCode::INTERNAL_SERVER_ERROR, Json(None)) };
-        let Some(user) = user else { return (StatusCode::FORBIDDEN, Json(None)) };
+            .await?;
+        let Some(user) = user else { return Err(AppError::NotFound) };
 
-        (StatusCode::OK, Json(Some(user)))
+        Ok((StatusCode::OK, Json(UserView::full(user))))
     }
 }
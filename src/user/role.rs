@@ -0,0 +1,256 @@
+use super::{User, UserView};
+use crate::api_token::Scope;
+use crate::error::{AppError, AppResult};
+use crate::session::{Credentials, Session};
+use crate::view::{Json, View};
+use crate::AppState;
+
+use axum::extract::{Path, State};
+use axum::http::StatusCode;
+use chrono::Utc;
+use mongodb::bson;
+use mongodb::bson::oid::ObjectId;
+use mongodb::options::{FindOneAndUpdateOptions, ReturnDocument};
+use serde::{Deserialize, Serialize};
+
+/// Something only some roles may do, checked with `Session::authorize`.
+#[derive(Deserialize, Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Permission {
+    /// Writing posts, and editing and deleting one's own.
+    #[serde(rename = "posts.create")]
+    PostsCreate,
+    /// Publishing, unpublishing and archiving one's own posts.
+    #[serde(rename = "posts.publish")]
+    PostsPublish,
+    /// Everything the author of a post may do with it, on anyone's posts.
+    #[serde(rename = "posts.edit_any")]
+    PostsEditAny,
+    /// Deleting anyone's comments.
+    #[serde(rename = "comments.moderate")]
+    CommentsModerate,
+    /// Renaming and merging tags.
+    #[serde(rename = "tags.manage")]
+    TagsManage,
+    /// Seeing and changing every account and user, and lifting lockouts.
+    #[serde(rename = "users.manage")]
+    UsersManage,
+    /// Changing the role of users.
+    #[serde(rename = "users.assign_roles")]
+    UsersAssignRoles,
+}
+
+const AUTHOR: &[Permission] = &[Permission::PostsCreate, Permission::PostsPublish];
+const EDITOR: &[Permission] = &[
+    Permission::PostsCreate,
+    Permission::PostsPublish,
+    Permission::PostsEditAny,
+    Permission::CommentsModerate,
+    Permission::TagsManage,
+];
+const ADMIN: &[Permission] = &[
+    Permission::PostsCreate,
+    Permission::PostsPublish,
+    Permission::PostsEditAny,
+    Permission::CommentsModerate,
+    Permission::TagsManage,
+    Permission::UsersManage,
+];
+const DEVELOPER: &[Permission] = &[
+    Permission::PostsCreate,
+    Permission::PostsPublish,
+    Permission::PostsEditAny,
+    Permission::CommentsModerate,
+    Permission::TagsManage,
+    Permission::UsersManage,
+    Permission::UsersAssignRoles,
+];
+
+#[derive(Deserialize, Serialize, Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub(crate) enum Role {
+    Developer,
+    Admin,
+    Editor,
+    /// Every user could post before roles had permissions, so the old `User` role
+    /// carries on as this one.
+    #[serde(alias = "User")]
+    Author,
+    /// Reads and comments only, what new users start as.
+    #[default]
+    Reader,
+}
+
+impl Role {
+    pub(crate) const ALL: [Role; 5] = [Role::Developer, Role::Admin, Role::Editor, Role::Author, Role::Reader];
+
+    pub(crate) fn permissions(self) -> &'static [Permission] {
+        match self {
+            Role::Developer => DEVELOPER,
+            Role::Admin => ADMIN,
+            Role::Editor => EDITOR,
+            Role::Author => AUTHOR,
+            Role::Reader => &[],
+        }
+    }
+
+    pub(crate) fn can(self, permission: Permission) -> bool {
+        self.permissions().contains(&permission)
+    }
+
+    /// Higher for roles with more permissions, each including those below.
+    fn rank(self) -> u8 {
+        match self {
+            Role::Developer => 4,
+            Role::Admin => 3,
+            Role::Editor => 2,
+            Role::Author => 1,
+            Role::Reader => 0,
+        }
+    }
+
+    /// Fails with `AppError::Forbidden` when `target` ranks above this role, so those
+    /// managing users cannot take over the accounts of who could demote them.
+    pub(crate) fn ensure_manages(self, target: Role) -> AppResult<()> {
+        if target.rank() > self.rank() {
+            return Err(AppError::Forbidden);
+        }
+        Ok(())
+    }
+}
+
+/// A role with everything it may do.
+#[derive(Serialize, Debug)]
+pub(crate) struct RoleView {
+    pub role: Role,
+    pub permissions: &'static [Permission],
+}
+
+impl View for RoleView {}
+
+#[derive(Deserialize)]
+pub(crate) struct ChangeRole {
+    pub role: Role,
+}
+
+impl User {
+    /// Whether this user may act on something they own, as told by `owner`, or else
+    /// on anyone's because their role grants `permission`.
+    pub(crate) fn owns_or_can(&self, owner: bool, permission: Permission) -> bool {
+        owner || self.role.can(permission)
+    }
+
+    /// Role of the user of account `auth_id`. Accounts without a user have no more
+    /// permissions than readers.
+    pub(crate) async fn role_of(state: &AppState, auth_id: ObjectId) -> AppResult<Role> {
+        let user = state
+            .users_collection
+            .find_one(bson::doc! { "auth_id": auth_id }, None)
+            .await?;
+
+        Ok(user.map_or(Role::Reader, |user| user.role))
+    }
+
+    /// Fails with `AppError::Forbidden` when user `id` ranks above `user`.
+    pub(super) async fn ensure_manages(state: &AppState, user: &User, id: ObjectId) -> AppResult<()> {
+        let target = state
+            .users_collection
+            .find_one(bson::doc! { "_id": id }, None)
+            .await?;
+        let Some(target) = target else { return Err(AppError::NotFound) };
+
+        user.role.ensure_manages(target.role)
+    }
+
+    /// Lists the roles there are and their permissions.
+    pub(crate) async fn read_roles(
+        credentials: Credentials,
+        State(state): State<AppState>,
+    ) -> AppResult<(StatusCode, Json<Vec<RoleView>>)> {
+        Session::authorize(&credentials, &state, Scope::Account, Permission::UsersAssignRoles).await?;
+
+        let roles = Role::ALL
+            .into_iter()
+            .map(|role| RoleView { role, permissions: role.permissions() })
+            .collect();
+
+        Ok((StatusCode::FOUND, Json(roles)))
+    }
+
+    /// Gives user `id` another role and signs them out everywhere, so no session
+    /// carries on with what the old role allowed. Developers cannot change their own,
+    /// so one is always left to change it back.
+    pub(crate) async fn change_role(
+        credentials: Credentials,
+        Path(id): Path<ObjectId>,
+        State(state): State<AppState>,
+        Json(json): Json<ChangeRole>,
+    ) -> AppResult<(StatusCode, Json<UserView>)> {
+        let developer = Session::authorize(&credentials, &state, Scope::Account, Permission::UsersAssignRoles).await?;
+        if developer.id == Some(id) {
+            return Err(AppError::Conflict(String::from("Developers cannot change their own role.")));
+        }
+
+        let options = FindOneAndUpdateOptions::builder()
+            .return_document(ReturnDocument::After)
+            .build();
+        let user = state
+            .users_collection
+            .find_one_and_update(
+                bson::doc! { "_id": id },
+                bson::doc! { "$set": {
+                    "role": bson::to_bson(&json.role)?,
+                    "updatedAt": bson::to_bson(&Utc::now())?,
+                } },
+                options,
+            )
+            .await?;
+        let Some(user) = user else { return Err(AppError::NotFound) };
+
+        Session::revoke_others(&state, user.auth_id, None).await?;
+
+        Ok((StatusCode::OK, Json(UserView::full(user))))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn roles_can_what_they_list() {
+        assert!(Role::Author.can(Permission::PostsCreate));
+        assert!(!Role::Author.can(Permission::PostsEditAny));
+        assert!(Role::Editor.can(Permission::TagsManage));
+        assert!(!Role::Editor.can(Permission::UsersManage));
+        assert!(Role::Admin.can(Permission::UsersManage));
+        assert!(!Role::Admin.can(Permission::UsersAssignRoles));
+        assert!(Role::Developer.can(Permission::UsersAssignRoles));
+        assert!(!Role::Reader.can(Permission::PostsCreate));
+    }
+
+    #[test]
+    fn each_role_can_everything_the_one_below_can() {
+        for pair in Role::ALL.windows(2) {
+            let (higher, lower) = (pair[0], pair[1]);
+            assert!(lower.permissions().iter().all(|permission| higher.can(*permission)));
+        }
+    }
+
+    #[test]
+    fn admins_cannot_manage_developers() {
+        let error = Role::Admin.ensure_manages(Role::Developer).unwrap_err();
+        assert_eq!(error.status(), StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn roles_manage_their_own_rank_and_below() {
+        assert!(Role::Admin.ensure_manages(Role::Admin).is_ok());
+        assert!(Role::Admin.ensure_manages(Role::Reader).is_ok());
+        assert!(Role::Developer.ensure_manages(Role::Developer).is_ok());
+        assert!(Role::Editor.ensure_manages(Role::Admin).is_err());
+    }
+
+    #[test]
+    fn old_user_role_reads_as_author() {
+        assert_eq!(serde_json::from_str::<Role>("\"User\"").unwrap(), Role::Author);
+    }
+}
@@ -1,38 +1,399 @@
 pub mod database {
+    use crate::error::AppResult;
+    use crate::session::Credentials;
+    use crate::utils::query::{ListParams, Page};
+    use crate::view::{Json, View};
     use crate::AppState;
     use async_trait::async_trait;
     use axum::extract::{Path, State};
-    use axum::headers::Cookie;
-    use axum::http::StatusCode;
-    use axum::{Json, TypedHeader};
+    use axum::http::{HeaderMap, StatusCode};
     use mongodb::bson;
+    use serde::de::DeserializeOwned;
 
     #[async_trait]
     pub(crate) trait Crud<T, U> {
+        /// Resource specific query string filters accepted by `read_all`.
+        type Filter: DeserializeOwned + Send;
+        /// What every route responds with instead of the stored document.
+        type View: View;
+
         async fn create(
-            cookie: TypedHeader<Cookie>,
+            credentials: Credentials,
             state: State<AppState>,
             json: Json<T>,
-        ) -> (StatusCode, Json<Option<U>>);
+        ) -> AppResult<(StatusCode, Json<Self::View>)>;
         async fn read_all(
-            cookie: TypedHeader<Cookie>,
+            credentials: Credentials,
+            params: ListParams<Self::Filter>,
             state: State<AppState>,
-        ) -> (StatusCode, Json<Option<Vec<U>>>);
+        ) -> AppResult<(StatusCode, HeaderMap, Json<Page<Self::View>>)>;
         async fn read(
-            cookie: TypedHeader<Cookie>,
+            credentials: Credentials,
             path: Path<bson::oid::ObjectId>,
             state: State<AppState>,
-        ) -> (StatusCode, Json<Option<U>>);
+        ) -> AppResult<(StatusCode, Json<Self::View>)>;
         async fn update(
-            cookie: TypedHeader<Cookie>,
+            credentials: Credentials,
             path: Path<bson::oid::ObjectId>,
             state: State<AppState>,
             json: Json<U>,
-        ) -> (StatusCode, Json<Option<U>>);
+        ) -> AppResult<(StatusCode, Json<Self::View>)>;
         async fn delete(
-            cookie: TypedHeader<Cookie>,
+            credentials: Credentials,
             path: Path<bson::oid::ObjectId>,
             state: State<AppState>,
-        ) -> (StatusCode, Json<Option<U>>);
+        ) -> AppResult<(StatusCode, Json<Self::View>)>;
+    }
+}
+
+pub mod query {
+    use crate::error::{AppError, AppResult};
+    use async_trait::async_trait;
+    use axum::extract::FromRequestParts;
+    use axum::http::header::LINK;
+    use axum::http::request::Parts;
+    use axum::http::{HeaderMap, HeaderValue, Uri};
+    use chrono::{DateTime, Utc};
+    use mongodb::bson::{self, doc, oid::ObjectId, Bson, Document};
+    use mongodb::options::FindOptions;
+    use mongodb::Collection;
+    use serde::de::DeserializeOwned;
+    use serde::{Deserialize, Serialize};
+
+    // needed to call .next() in mongodb Cursor type
+    use futures::StreamExt;
+
+    const DEFAULT_LIMIT: u32 = 20;
+    const MAX_LIMIT: u32 = 100;
+
+    /// Page size for a requested `limit`, shared by every listing that pages its results.
+    pub(crate) fn page_limit(limit: Option<u32>) -> u32 {
+        limit.unwrap_or(DEFAULT_LIMIT).clamp(1, MAX_LIMIT)
+    }
+
+    #[derive(Deserialize, Serialize, Debug, PartialEq, Clone, Copy, Default)]
+    pub(crate) enum Sort {
+        #[serde(rename = "createdAt")]
+        CreatedAt,
+        #[default]
+        #[serde(rename = "-createdAt")]
+        CreatedAtDesc,
+        #[serde(rename = "title")]
+        Title,
+    }
+
+    impl Sort {
+        fn field(&self) -> &'static str {
+            match self {
+                Sort::CreatedAt | Sort::CreatedAtDesc => "createdAt",
+                Sort::Title => "title",
+            }
+        }
+
+        fn direction(&self) -> i32 {
+            match self {
+                Sort::CreatedAt | Sort::Title => 1,
+                Sort::CreatedAtDesc => -1,
+            }
+        }
+    }
+
+    /// Query string shared by every `read_all` listing.
+    #[derive(Deserialize, Debug, Default)]
+    pub(crate) struct ListQuery {
+        pub limit: Option<u32>,
+        pub after: Option<ObjectId>,
+        #[serde(default)]
+        pub sort: Sort,
+        pub since: Option<DateTime<Utc>>,
+        pub until: Option<DateTime<Utc>>,
+    }
+
+    /// Filters for resources that only support the shared `ListQuery` parameters.
+    #[derive(Deserialize, Debug, Default)]
+    pub(crate) struct NoFilter {}
+
+    /// Extracts a `ListQuery` together with the resource filter `F` from the query string.
+    pub(crate) struct ListParams<F> {
+        pub query: ListQuery,
+        pub filter: F,
+        uri: Uri,
+    }
+
+    #[async_trait]
+    impl<S, F> FromRequestParts<S> for ListParams<F>
+    where
+        S: Send + Sync,
+        F: DeserializeOwned,
+    {
+        type Rejection = AppError;
+
+        async fn from_request_parts(parts: &mut Parts, _: &S) -> Result<Self, Self::Rejection> {
+            let raw_query = parts.uri.query().unwrap_or_default();
+            let query = serde_urlencoded::from_str::<ListQuery>(raw_query)
+                .map_err(|error| AppError::BadRequest(error.to_string()))?;
+            let filter = serde_urlencoded::from_str::<F>(raw_query)
+                .map_err(|error| AppError::BadRequest(error.to_string()))?;
+
+            Ok(ListParams {
+                query,
+                filter,
+                uri: parts.uri.clone(),
+            })
+        }
+    }
+
+    #[derive(Serialize, Debug, PartialEq)]
+    pub(crate) struct Page<T> {
+        pub items: Vec<T>,
+        /// Cursor to pass as `?after=` to fetch the following page.
+        pub next: Option<String>,
+    }
+
+    impl<T> Page<T> {
+        /// Converts every item, keeping the cursor.
+        pub(crate) fn map<V>(self, f: impl FnMut(T) -> V) -> Page<V> {
+            Page {
+                items: self.items.into_iter().map(f).collect(),
+                next: self.next,
+            }
+        }
+    }
+
+    impl<F> ListParams<F> {
+        fn limit(&self) -> u32 {
+            page_limit(self.query.limit)
+        }
+
+        /// Builds the `Link` header pointing at the page after `next`, keeping every
+        /// other query parameter of the current request.
+        pub(crate) fn link_headers(&self, next: &Option<String>) -> HeaderMap {
+            let mut headers = HeaderMap::new();
+            let Some(next) = next else { return headers };
+
+            let raw_query = self.uri.query().unwrap_or_default();
+            let mut pairs: Vec<(String, String)> =
+                serde_urlencoded::from_str(raw_query).unwrap_or_default();
+            pairs.retain(|(key, _)| key != "after");
+            pairs.push((String::from("after"), next.clone()));
+            let Ok(query) = serde_urlencoded::to_string(pairs) else { return headers };
+
+            let link = format!("<{}?{}>; rel=\"next\"", self.uri.path(), query);
+            if let Ok(link) = HeaderValue::from_str(&link) {
+                headers.insert(LINK, link);
+            }
+            headers
+        }
+
+        /// Runs a paginated `find` over `collection`, combining `filter` with the shared
+        /// date range and the keyset condition for the `after` cursor. Fails with
+        /// `AppError::BadRequest` when the cursor's item is gone, since the page after
+        /// it can no longer be told apart from the first one.
+        pub(crate) async fn find_page<T>(
+            &self,
+            collection: &Collection<T>,
+            filter: Document,
+        ) -> AppResult<Page<T>>
+        where
+            T: DeserializeOwned,
+        {
+            let documents = collection.clone_with_type::<Document>();
+            let sort = self.query.sort;
+            let field = sort.field();
+            let direction = sort.direction();
+
+            let mut conditions = vec![filter];
+
+            let mut created_at = Document::new();
+            if let Some(since) = self.query.since {
+                created_at.insert("$gte", bson::to_bson(&since)?);
+            }
+            if let Some(until) = self.query.until {
+                created_at.insert("$lte", bson::to_bson(&until)?);
+            }
+            if !created_at.is_empty() {
+                conditions.push(doc! { "createdAt": created_at });
+            }
+
+            if let Some(after) = self.query.after {
+                let after_document = documents.find_one(doc! { "_id": after }, None).await?;
+                let Some(after_document) = after_document else {
+                    return Err(AppError::BadRequest(String::from(
+                        "The `after` cursor points at an item that no longer exists, start again from the first page.",
+                    )));
+                };
+                let value = after_document.get(field).cloned().unwrap_or(Bson::Null);
+                let operator = if direction > 0 { "$gt" } else { "$lt" };
+                conditions.push(doc! {
+                    "$or": [
+                        { field: { operator: value.clone() } },
+                        { field: value, "_id": { operator: after } },
+                    ]
+                });
+            }
+
+            let limit = self.limit();
+            let options = FindOptions::builder()
+                .sort(doc! { field: direction, "_id": direction })
+                .limit(i64::from(limit) + 1)
+                .build();
+            let mut cursor = documents
+                .find(doc! { "$and": conditions }, options)
+                .await?;
+
+            let mut page: Vec<Document> = vec![];
+            while let Some(document) = cursor.next().await {
+                page.push(document?);
+            }
+
+            let next = if page.len() > limit as usize {
+                page.truncate(limit as usize);
+                page.last()
+                    .and_then(|document| document.get_object_id("_id").ok())
+                    .map(|id| id.to_hex())
+            } else {
+                None
+            };
+
+            let mut items: Vec<T> = vec![];
+            for document in page {
+                items.push(bson::from_document(document)?);
+            }
+
+            Ok(Page { items, next })
+        }
+    }
+}
+
+pub mod slug {
+    /// Turns `text` into a lowercase, dash separated, URL safe slug.
+    pub(crate) fn slugify(text: &str) -> String {
+        let mut slug = String::with_capacity(text.len());
+        for character in text.chars() {
+            if character.is_ascii_alphanumeric() {
+                slug.push(character.to_ascii_lowercase());
+            } else if !slug.is_empty() && !slug.ends_with('-') {
+                slug.push('-');
+            }
+        }
+        while slug.ends_with('-') {
+            slug.pop();
+        }
+        slug
+    }
+
+    /// Slugs to try for `text` in turn until one is free: the slug itself, then with
+    /// `-2`, `-3` and so on appended. `fallback` stands in for text without any
+    /// letters or digits.
+    pub(crate) fn candidates(text: &str, fallback: &str) -> impl Iterator<Item = String> {
+        let mut base = slugify(text);
+        if base.is_empty() {
+            base = String::from(fallback);
+        }
+
+        (1..).map(move |suffix| match suffix {
+            1 => base.clone(),
+            suffix => format!("{base}-{suffix}"),
+        })
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn slugify_lowercases_and_joins_words_with_dashes() {
+            assert_eq!(slugify("Hello, World!"), "hello-world");
+            assert_eq!(slugify("  Rust   2024 -- Edition "), "rust-2024-edition");
+        }
+
+        #[test]
+        fn slugify_drops_non_ascii_and_edge_dashes() {
+            assert_eq!(slugify("--Crème brûlée--"), "cr-me-br-l-e");
+            assert_eq!(slugify("!!!"), "");
+        }
+
+        #[test]
+        fn candidates_start_with_the_slug_then_count_up_from_two() {
+            let slugs: Vec<String> = candidates("My Post", "post").take(3).collect();
+            assert_eq!(slugs, ["my-post", "my-post-2", "my-post-3"]);
+        }
+
+        #[test]
+        fn candidates_fall_back_for_titles_without_slug_characters() {
+            let slugs: Vec<String> = candidates("???", "post").take(2).collect();
+            assert_eq!(slugs, ["post", "post-2"]);
+        }
+    }
+}
+
+pub mod token {
+    use hmac::{Hmac, Mac};
+    use sha2::{Digest, Sha256};
+
+    fn signature(secret: &[u8], payload: &str) -> String {
+        let Ok(mut mac) = Hmac::<Sha256>::new_from_slice(secret) else { return String::new() };
+        mac.update(payload.as_bytes());
+        format!("{:x}", mac.finalize().into_bytes())
+    }
+
+    /// Appends an HMAC-SHA256 of `payload` under `secret`, so the server can later
+    /// trust the payload without storing it.
+    pub(crate) fn sign(secret: &[u8], payload: &str) -> String {
+        format!("{payload}.{}", signature(secret, payload))
+    }
+
+    /// Returns the payload of a token made by `sign`, or `None` if it was tampered with.
+    pub(crate) fn verify<'a>(secret: &[u8], token: &'a str) -> Option<&'a str> {
+        let (payload, signature) = token.rsplit_once('.')?;
+        if !constant_time_eq(&self::signature(secret, payload), signature) {
+            return None;
+        }
+        Some(payload)
+    }
+
+    /// Hex SHA-256 of a random token, which is what gets stored instead of the token.
+    pub(crate) fn hash(token: &str) -> String {
+        format!("{:x}", Sha256::digest(token.as_bytes()))
+    }
+
+    /// Compares in constant time, so a secret cannot be guessed byte by byte.
+    pub(crate) fn constant_time_eq(left: &str, right: &str) -> bool {
+        left.len() == right.len()
+            && left
+                .bytes()
+                .zip(right.bytes())
+                .fold(0, |difference, (left, right)| difference | (left ^ right))
+                == 0
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        const SECRET: &[u8] = b"secret";
+
+        #[test]
+        fn verify_returns_the_signed_payload() {
+            let token = sign(SECRET, "auth.1700000000.nonce");
+            assert_eq!(verify(SECRET, &token), Some("auth.1700000000.nonce"));
+        }
+
+        #[test]
+        fn verify_rejects_tampered_tokens() {
+            let token = sign(SECRET, "auth.1700000000.nonce");
+            let tampered = token.replacen("auth", "other", 1);
+
+            assert_eq!(verify(SECRET, &tampered), None);
+            assert_eq!(verify(b"another secret", &token), None);
+            assert_eq!(verify(SECRET, "no signature"), None);
+        }
+
+        #[test]
+        fn constant_time_eq_compares_whole_strings() {
+            assert!(constant_time_eq("abc", "abc"));
+            assert!(!constant_time_eq("abc", "abd"));
+            assert!(!constant_time_eq("abc", "abcd"));
+        }
     }
 }
@@ -0,0 +1,53 @@
+use crate::error::AppError;
+use crate::utils::query::Page;
+
+use async_trait::async_trait;
+use axum::body::HttpBody;
+use axum::extract::FromRequest;
+use axum::http::Request;
+use axum::response::{IntoResponse, Response};
+use axum::BoxError;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+/// Marks the types a route may send back to clients. Storage structs such as `Auth`
+/// or `User` never implement it, they are converted into a view first, so a field
+/// like `password_hash` cannot end up in a response by accident.
+pub(crate) trait View: Serialize {}
+
+impl<T: View> View for Page<T> {}
+
+impl<T: View> View for Vec<T> {}
+
+/// Drop-in for `axum::Json` that only serializes `View` types. Routes must use this
+/// one, `axum::Json` is a disallowed type in `clippy.toml`.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Json<T>(pub T);
+
+#[async_trait]
+impl<T, S, B> FromRequest<S, B> for Json<T>
+where
+    T: DeserializeOwned,
+    B: HttpBody + Send + 'static,
+    B::Data: Send,
+    B::Error: Into<BoxError>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(request: Request<B>, state: &S) -> Result<Self, Self::Rejection> {
+        #[allow(clippy::disallowed_types)]
+        let axum::Json(value) = axum::Json::<T>::from_request(request, state)
+            .await
+            .map_err(|rejection| AppError::BadRequest(rejection.body_text()))?;
+
+        Ok(Json(value))
+    }
+}
+
+impl<T: View> IntoResponse for Json<T> {
+    fn into_response(self) -> Response {
+        #[allow(clippy::disallowed_types)]
+        axum::Json(self.0).into_response()
+    }
+}